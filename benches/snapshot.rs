@@ -0,0 +1,79 @@
+//! Compares the time to load a `CoreBPE` from a `.tiktoken` file with the time to load it from a
+//! snapshot. Both files are read from memory, so this measures parsing and construction only.
+//!
+//! Run with `cargo bench --bench snapshot`.
+
+use std::time::{Duration, Instant};
+
+use rustc_hash::FxHashMap as HashMap;
+use tiktoken::Rank;
+use tiktoken::encoding::EncodingSpec;
+use tiktoken::openai_public::CL100K_PAT_STR;
+
+fn synthetic_spec(n_vocab: usize) -> EncodingSpec {
+    let mut mergeable_ranks: HashMap<Vec<u8>, Rank> =
+        (0..=255u8).map(|b| (vec![b], Rank::from(b))).collect();
+    // Roughly the length distribution of real vocabularies
+    let mut state: u64 = 0x2545f4914f6cdd1d;
+    while mergeable_ranks.len() < n_vocab {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let len = 2 + (state % 9) as usize;
+        let token: Vec<u8> = (0..len)
+            .map(|i| b"etaoinshrdlu "[((state >> (i * 4)) % 13) as usize])
+            .collect();
+        let rank = mergeable_ranks.len() as Rank;
+        mergeable_ranks.entry(token).or_insert(rank);
+    }
+    EncodingSpec {
+        name: "synthetic".to_string(),
+        pat_str: CL100K_PAT_STR.to_string(),
+        mergeable_ranks,
+        special_tokens: HashMap::from_iter([("<|endoftext|>".to_string(), n_vocab as Rank)]),
+        explicit_n_vocab: None,
+    }
+}
+
+fn median_of<F: FnMut()>(iterations: usize, mut f: F) -> Duration {
+    let mut times: Vec<Duration> = (0..iterations)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    times[iterations / 2]
+}
+
+fn main() {
+    for n_vocab in [50_000, 100_000, 200_000] {
+        let spec = synthetic_spec(n_vocab);
+        let bpe = spec.to_core_bpe().unwrap();
+        let mut tiktoken_file = Vec::new();
+        tiktoken::load::dump_tiktoken_bpe(&spec.mergeable_ranks, &mut tiktoken_file).unwrap();
+        let mut snapshot = Vec::new();
+        bpe.write_snapshot(&mut snapshot).unwrap();
+
+        let from_tiktoken_file = median_of(11, || {
+            let mergeable_ranks =
+                tiktoken::load::load_tiktoken_bpe(tiktoken_file.as_slice(), None).unwrap();
+            let spec = EncodingSpec {
+                name: spec.name.clone(),
+                pat_str: spec.pat_str.clone(),
+                mergeable_ranks,
+                special_tokens: spec.special_tokens.clone(),
+                explicit_n_vocab: None,
+            };
+            std::hint::black_box(spec.into_core_bpe().unwrap());
+        });
+        let from_snapshot = median_of(11, || {
+            std::hint::black_box(tiktoken::CoreBPE::from_snapshot(&snapshot).unwrap());
+        });
+        println!(
+            "n_vocab={n_vocab:>7}  from .tiktoken: {from_tiktoken_file:>10.2?}  from snapshot: {from_snapshot:>10.2?}  ({} byte snapshot)",
+            snapshot.len()
+        );
+    }
+}
@@ -0,0 +1,372 @@
+//! Encoding and decoding batches of documents, or single large documents, on multiple threads.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::openai_public::{CL100K_PAT_STR, O200K_PAT_STR, R50K_PAT_STR};
+use crate::{
+    AllowedSpecial, CoreBPE, DecodeError, DecodeKeyError, DecodeMode, EncodeError, MAX_NUM_THREADS,
+    Rank,
+};
+
+/// Calls `f` on every item on up to `num_threads` scoped threads, returning the results in order.
+///
+/// Each thread gets a fresh thread id, so up to `MAX_NUM_THREADS` threads each get their own
+/// regex from `_get_tl_regex`. More threads than that would share regexes, so `num_threads` is
+/// capped there. A `num_threads` of 0 or 1 runs on the calling thread.
+fn map_parallel<T, R>(items: &[T], num_threads: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+{
+    let num_threads = num_threads.min(items.len()).min(MAX_NUM_THREADS);
+    if num_threads <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    // Threads take items one at a time, so one long document doesn't hold up a whole share
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<Option<R>> = items.iter().map(|_| None).collect();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..num_threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = vec![];
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(i) else {
+                            return done;
+                        };
+                        done.push((i, f(item)));
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            for (i, result) in worker.join().unwrap() {
+                results[i] = Some(result);
+            }
+        }
+    });
+    results.into_iter().map(Option::unwrap).collect()
+}
+
+/// Patterns for which `is_safe_split` has been checked, by `test_safe_splits`.
+const SPLITTABLE_PATTERNS: &[&str] = &[R50K_PAT_STR, CL100K_PAT_STR, O200K_PAT_STR];
+
+/// Segments of a document encoded in parallel are about this long, or longer.
+const MIN_SEGMENT_LEN: usize = 1 << 16;
+
+/// Whether splitting `text` into regex pieces gives the same pieces as splitting `text[..i]`
+/// and `text[i..]` separately, for each of `SPLITTABLE_PATTERNS`.
+///
+/// That holds after a newline that follows a non-whitespace character, when a letter or digit
+/// comes next. No alternative matches the non-whitespace character together with whitespace
+/// after it, except o200k's punctuation followed by `[\r\n/]*`, which stops at the newline just
+/// like at the end of the text. So the newline starts a piece, or ends a punctuation piece. A
+/// newline piece on its own is matched the same way by `\s*[\r\n]`, `\s++$` or `\s` whether or
+/// not the text ends there, and no alternative can start with a newline and go on to a letter or
+/// digit, so the next piece starts right after it.
+fn is_safe_split(text: &str, i: usize) -> bool {
+    let Some(before) = text[..i].strip_suffix('\n') else {
+        return false;
+    };
+    before
+        .chars()
+        .next_back()
+        .is_some_and(|c| !c.is_whitespace())
+        && text[i..].chars().next().is_some_and(char::is_alphanumeric)
+}
+
+/// Splits `text` at safe points into about `n_segments` segments of similar length.
+fn split_segments(text: &str, n_segments: usize) -> Vec<&str> {
+    let mut segments = vec![];
+    let mut start = 0;
+    for k in 1..n_segments {
+        let target = text.len() / n_segments * k;
+        if target <= start {
+            continue;
+        }
+        let split = text.as_bytes()[target..]
+            .iter()
+            .enumerate()
+            .filter(|&(_, &b)| b == b'\n')
+            .map(|(j, _)| target + j + 1)
+            .find(|&i| is_safe_split(text, i));
+        let Some(split) = split else {
+            break;
+        };
+        segments.push(&text[start..split]);
+        start = split;
+    }
+    segments.push(&text[start..]);
+    segments
+}
+
+impl CoreBPE {
+    /// Splits `text` into segments of at least about `min_segment_len` bytes that can be encoded
+    /// separately, if that is worth doing.
+    fn parallel_segments<'a>(
+        &self,
+        text: &'a str,
+        num_threads: usize,
+        min_segment_len: usize,
+    ) -> Vec<&'a str> {
+        // Split several segments per thread, since safe split points may be unevenly spread
+        let n_segments = (num_threads.min(MAX_NUM_THREADS) * 4).min(text.len() / min_segment_len);
+        // Other patterns may match across newlines. A special token containing a newline could
+        // span a split point too, and even a disallowed one changes where allowed ones are found.
+        if n_segments <= 1
+            || !SPLITTABLE_PATTERNS.contains(&self.pattern())
+            || self.special_tokens_encoder.keys().any(|s| s.contains('\n'))
+        {
+            return vec![text];
+        }
+        split_segments(text, n_segments)
+    }
+
+    /// Like `encode_ordinary`, but splits a large `text` into segments that are encoded on up to
+    /// `num_threads` threads.
+    ///
+    /// Text is only split where that cannot change the result, so this always returns the same
+    /// tokens as `encode_ordinary`. Splitting is only done for the patterns in
+    /// [`crate::openai_public`], and text shorter than about a hundred kilobytes is encoded on the
+    /// calling thread.
+    pub fn encode_ordinary_parallel(&self, text: &str, num_threads: usize) -> Vec<Rank> {
+        let segments = self.parallel_segments(text, num_threads, MIN_SEGMENT_LEN);
+        self.encode_ordinary_batch(&segments, num_threads).concat()
+    }
+
+    /// Like `encode`, but splits a large `text` into segments that are encoded on up to
+    /// `num_threads` threads. See `encode_ordinary_parallel`.
+    pub fn encode_parallel<'a>(
+        &self,
+        text: &str,
+        allowed_special: impl Into<AllowedSpecial<'a>>,
+        num_threads: usize,
+    ) -> Result<Vec<Rank>, EncodeError> {
+        let segments = self.parallel_segments(text, num_threads, MIN_SEGMENT_LEN);
+        Ok(self
+            .encode_batch(&segments, allowed_special, num_threads)?
+            .concat())
+    }
+
+    /// Like `encode_ordinary` on each text, on up to `num_threads` threads.
+    pub fn encode_ordinary_batch<S>(&self, texts: &[S], num_threads: usize) -> Vec<Vec<Rank>>
+    where
+        S: AsRef<str> + Sync,
+    {
+        map_parallel(texts, num_threads, |text| {
+            self.encode_ordinary(text.as_ref())
+        })
+    }
+
+    /// Like `encode` on each text, on up to `num_threads` threads. If any text fails to encode,
+    /// returns the error for the first one.
+    pub fn encode_batch<'a, S>(
+        &self,
+        texts: &[S],
+        allowed_special: impl Into<AllowedSpecial<'a>>,
+        num_threads: usize,
+    ) -> Result<Vec<Vec<Rank>>, EncodeError>
+    where
+        S: AsRef<str> + Sync,
+    {
+        let allowed_special = allowed_special.into();
+        map_parallel(texts, num_threads, |text| {
+            let (tokens, _) = self.encode(text.as_ref(), allowed_special)?;
+            Ok(tokens)
+        })
+        .into_iter()
+        .collect()
+    }
+
+    /// Like `decode` on each list of tokens, on up to `num_threads` threads.
+    pub fn decode_batch<T>(
+        &self,
+        batch: &[T],
+        mode: DecodeMode,
+        num_threads: usize,
+    ) -> Result<Vec<String>, DecodeError>
+    where
+        T: AsRef<[Rank]> + Sync,
+    {
+        map_parallel(batch, num_threads, |tokens| {
+            self.decode(tokens.as_ref(), mode)
+        })
+        .into_iter()
+        .collect()
+    }
+
+    /// Like `decode_bytes` on each list of tokens, on up to `num_threads` threads.
+    pub fn decode_bytes_batch<T>(
+        &self,
+        batch: &[T],
+        num_threads: usize,
+    ) -> Result<Vec<Vec<u8>>, DecodeKeyError>
+    where
+        T: AsRef<[Rank]> + Sync,
+    {
+        map_parallel(batch, num_threads, |tokens| {
+            self.decode_bytes(tokens.as_ref())
+        })
+        .into_iter()
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rustc_hash::FxHashMap as HashMap;
+
+    use super::{MIN_SEGMENT_LEN, SPLITTABLE_PATTERNS, is_safe_split};
+    use crate::{CoreBPE, DecodeMode, Rank};
+
+    fn setup_bpe() -> CoreBPE {
+        let mut encoder: HashMap<Vec<u8>, Rank> =
+            (0..=255u8).map(|b| (vec![b], b.into())).collect();
+        encoder.insert(b"ab".to_vec(), 256);
+        let special_tokens = HashMap::from_iter([("<|endoftext|>".to_string(), 257)]);
+        CoreBPE::new_internal(encoder, special_tokens, r"\w+|\s+|[^\w\s]+").unwrap()
+    }
+
+    #[test]
+    fn test_batch_matches_serial() {
+        let bpe = setup_bpe();
+        let texts: Vec<String> = (0..50)
+            .map(|i| format!("{} é<|endoftext|>", "ab c ".repeat(i)))
+            .collect();
+        let allowed_special = HashSet::from(["<|endoftext|>"]);
+        let expected: Vec<Vec<Rank>> = texts
+            .iter()
+            .map(|text| bpe.encode(text, &allowed_special).unwrap().0)
+            .collect();
+        let expected_ordinary: Vec<Vec<Rank>> =
+            texts.iter().map(|text| bpe.encode_ordinary(text)).collect();
+
+        for num_threads in [0, 1, 3, 8, 200] {
+            let tokens = bpe
+                .encode_batch(&texts, &allowed_special, num_threads)
+                .unwrap();
+            assert_eq!(tokens, expected);
+            assert_eq!(
+                bpe.encode_ordinary_batch(&texts, num_threads),
+                expected_ordinary
+            );
+            assert_eq!(
+                bpe.decode_batch(&tokens, DecodeMode::Strict, num_threads)
+                    .unwrap(),
+                texts
+            );
+            let bytes = bpe.decode_bytes_batch(&tokens, num_threads).unwrap();
+            assert_eq!(bytes[3], texts[3].as_bytes());
+        }
+
+        assert!(
+            bpe.encode_batch::<&str>(&[], &allowed_special, 4)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    /// Checks `is_safe_split` exhaustively on random text, for each shipped pattern.
+    #[test]
+    fn test_safe_splits() {
+        let alphabet: Vec<char> = "aZ9é中 \t\n\r\n\n'/.,!-_s".chars().collect();
+        let mut state: u64 = 0x2545f4914f6cdd1d;
+        let mut random = |n: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize % n
+        };
+
+        for pattern in SPLITTABLE_PATTERNS {
+            let regex = fancy_regex::Regex::new(pattern).unwrap();
+            let pieces = |text: &str, offset: usize| -> Vec<(usize, usize)> {
+                regex
+                    .find_iter(text)
+                    .map(|m| {
+                        let m = m.unwrap();
+                        (offset + m.start(), offset + m.end())
+                    })
+                    .collect()
+            };
+            let mut n_checked = 0;
+            for _ in 0..3000 {
+                let text: String = (0..random(40))
+                    .map(|_| alphabet[random(alphabet.len())])
+                    .collect();
+                let expected = pieces(&text, 0);
+                for i in 0..=text.len() {
+                    if !text.is_char_boundary(i) || !is_safe_split(&text, i) {
+                        continue;
+                    }
+                    let mut split = pieces(&text[..i], 0);
+                    split.extend(pieces(&text[i..], i));
+                    assert_eq!(split, expected, "{pattern} {text:?} split at {i}");
+                    n_checked += 1;
+                }
+            }
+            assert!(n_checked > 1000);
+        }
+    }
+
+    #[test]
+    fn test_encode_parallel() {
+        let mut encoder: HashMap<Vec<u8>, Rank> =
+            (0..=255u8).map(|b| (vec![b], b.into())).collect();
+        for (i, token) in ["ab", " ab", "\n\n", "ab\n"].iter().enumerate() {
+            encoder.insert(token.as_bytes().to_vec(), 256 + i as Rank);
+        }
+        let special_tokens = HashMap::from_iter([("<|endoftext|>".to_string(), 300)]);
+        // Short segments keep the text small
+        let min_segment_len = 1 << 10;
+        let line = "ab abab.\nab 12/ab\n\n  é<|endoftext|>ab \nab\n";
+        let text = line.repeat(20 * min_segment_len / line.len());
+        let allowed_special = HashSet::from(["<|endoftext|>"]);
+        for pattern in SPLITTABLE_PATTERNS {
+            let bpe =
+                CoreBPE::new_internal(encoder.clone(), special_tokens.clone(), pattern).unwrap();
+            let segments = bpe.parallel_segments(&text, 4, min_segment_len);
+            assert!(segments.len() > 4);
+            assert_eq!(segments.concat(), text);
+            assert_eq!(
+                bpe.encode_ordinary_batch(&segments, 4).concat(),
+                bpe.encode_ordinary(&text)
+            );
+            assert_eq!(
+                bpe.encode_batch(&segments, &allowed_special, 4)
+                    .unwrap()
+                    .concat(),
+                bpe.encode(&text, &allowed_special).unwrap().0
+            );
+
+            // This text is too short to split with the real segment length
+            assert_eq!(bpe.parallel_segments(&text, 4, MIN_SEGMENT_LEN).len(), 1);
+            assert_eq!(
+                bpe.encode_ordinary_parallel(&text, 4),
+                bpe.encode_ordinary(&text)
+            );
+            assert_eq!(
+                bpe.encode_parallel(&text, &allowed_special, 4).unwrap(),
+                bpe.encode(&text, &allowed_special).unwrap().0
+            );
+        }
+
+        // Unknown patterns are not split
+        let bpe = CoreBPE::new_internal(encoder, special_tokens, r"\S+|\s+").unwrap();
+        assert_eq!(bpe.parallel_segments(&text, 4, min_segment_len).len(), 1);
+    }
+
+    #[test]
+    fn test_batch_errors() {
+        let bpe = setup_bpe();
+        let batch = [vec![256], vec![1000], vec![2000]];
+        let err = bpe.decode_bytes_batch(&batch, 2).unwrap_err();
+        assert_eq!(err.token, 1000);
+        assert!(bpe.decode_batch(&batch, DecodeMode::Replace, 2).is_err());
+    }
+}
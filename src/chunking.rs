@@ -0,0 +1,233 @@
+//! Splitting documents into chunks of at most a given number of tokens.
+
+use crate::{CoreBPE, Rank};
+
+/// A chunk of a document, from [`CoreBPE::chunk`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk<'a> {
+    /// `text[start..end]` of the document.
+    pub text: &'a str,
+    /// Byte offsets of the chunk in the document, on character boundaries.
+    pub start: usize,
+    pub end: usize,
+    /// `encode_ordinary(text)`.
+    pub tokens: Vec<Rank>,
+}
+
+/// What a chunk boundary falls on, from least to most preferred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum BoundaryKind {
+    /// Between two tokens of the same regex piece.
+    Token,
+    /// Between two regex pieces.
+    Piece,
+    /// Before or after whitespace.
+    Whitespace,
+    /// After a newline.
+    Line,
+    /// After a blank line.
+    Paragraph,
+}
+
+/// A token boundary in the document that is also a character boundary.
+#[derive(Debug, Clone, Copy)]
+struct Boundary {
+    /// The number of tokens before the boundary in the encoding of the whole document.
+    token: usize,
+    byte: usize,
+    kind: BoundaryKind,
+}
+
+fn boundary_kind(text: &str, byte: usize, piece_start: bool) -> BoundaryKind {
+    if !piece_start {
+        return BoundaryKind::Token;
+    }
+    let (before, after) = text.split_at(byte);
+    if let Some(line) = before.strip_suffix('\n') {
+        let line = line.trim_end_matches([' ', '\t', '\r']);
+        if line.is_empty() || line.ends_with('\n') {
+            return BoundaryKind::Paragraph;
+        }
+        return BoundaryKind::Line;
+    }
+    let is_whitespace = |c: Option<char>| c.is_some_and(char::is_whitespace);
+    if is_whitespace(before.chars().next_back()) || is_whitespace(after.chars().next()) {
+        return BoundaryKind::Whitespace;
+    }
+    BoundaryKind::Piece
+}
+
+impl CoreBPE {
+    /// Returns the token boundaries of `text` that are character boundaries, including the start
+    /// and end of `text`.
+    fn chunk_boundaries(&self, text: &str) -> Vec<Boundary> {
+        let regex = self._get_tl_regex();
+        let mut boundaries = vec![];
+        let mut tokens = vec![];
+        let mut n_tokens = 0;
+        for mat in regex.find_iter(text) {
+            let mat = mat.unwrap();
+            tokens.clear();
+            self.encode_piece(mat.as_str().as_bytes(), &mut tokens);
+            let mut byte = mat.start();
+            for (i, token) in tokens.iter().enumerate() {
+                if text.is_char_boundary(byte) {
+                    boundaries.push(Boundary {
+                        token: n_tokens + i,
+                        byte,
+                        kind: boundary_kind(text, byte, i == 0),
+                    });
+                }
+                byte += self.vocab[*token].len();
+            }
+            n_tokens += tokens.len();
+        }
+        boundaries.push(Boundary {
+            token: n_tokens,
+            byte: text.len(),
+            kind: BoundaryKind::Paragraph,
+        });
+        boundaries
+    }
+
+    /// Splits `text` into chunks of at most `max_tokens` tokens, where consecutive chunks share
+    /// up to `overlap` tokens.
+    ///
+    /// Chunks start and end on token and character boundaries. Among the places a chunk could
+    /// end, paragraph breaks are preferred over line breaks, then whitespace, then regex piece
+    /// boundaries, and then any other token boundary, as long as the chunk stays at least half
+    /// as long as it could be. Each chunk's tokens are the encoding of its own text, so the text
+    /// of a chunk always encodes to at most `max_tokens` tokens. The only exception is text between
+    /// two adjacent boundaries that encodes to more than `max_tokens` tokens on its own, such as
+    /// a character made of several tokens, which becomes a chunk of its own.
+    ///
+    /// Special tokens are encoded as ordinary text, like in `encode_ordinary`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_tokens` is 0 or `overlap` is not less than `max_tokens`.
+    pub fn chunk<'a>(&self, text: &'a str, max_tokens: usize, overlap: usize) -> Vec<Chunk<'a>> {
+        assert!(max_tokens > 0, "max_tokens must be positive");
+        assert!(overlap < max_tokens, "overlap must be less than max_tokens");
+
+        let boundaries = self.chunk_boundaries(text);
+        let n_tokens = boundaries.last().unwrap().token;
+        // Indices into `boundaries`
+        let mut start = 0;
+        let mut chunks = vec![];
+        while start + 1 < boundaries.len() {
+            let start_token = boundaries[start].token;
+            let mut limit = start_token + max_tokens;
+            let (end, tokens) = loop {
+                let end = if n_tokens <= limit {
+                    boundaries.len() - 1
+                } else {
+                    // Prefer the best kind of boundary, then the longest chunk
+                    let min_token = start_token + overlap.max(max_tokens / 2);
+                    let best = |min_token| {
+                        (start + 1..boundaries.len())
+                            .take_while(|&i| boundaries[i].token <= limit)
+                            .filter(|&i| boundaries[i].token > min_token)
+                            .max_by_key(|&i| (boundaries[i].kind, i))
+                    };
+                    best(min_token)
+                        .or_else(|| best(start_token))
+                        .unwrap_or(start + 1)
+                };
+                let chunk_text = &text[boundaries[start].byte..boundaries[end].byte];
+                let tokens = self.encode_ordinary(chunk_text);
+                if tokens.len() <= max_tokens || end == start + 1 {
+                    break (end, tokens);
+                }
+                // Re-encoding the chunk on its own can take more tokens, so try a shorter one
+                limit = boundaries[end]
+                    .token
+                    .saturating_sub(tokens.len() - max_tokens);
+            };
+
+            chunks.push(Chunk {
+                text: &text[boundaries[start].byte..boundaries[end].byte],
+                start: boundaries[start].byte,
+                end: boundaries[end].byte,
+                tokens,
+            });
+            if end == boundaries.len() - 1 {
+                break;
+            }
+
+            // Start the next chunk up to `overlap` tokens back, at a piece boundary if possible
+            let overlap_token = boundaries[end].token.saturating_sub(overlap);
+            let first = (start + 1..=end)
+                .find(|&i| boundaries[i].token >= overlap_token)
+                .unwrap();
+            start = (first..end)
+                .find(|&i| boundaries[i].kind >= BoundaryKind::Piece)
+                .unwrap_or(first);
+        }
+        chunks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_hash::FxHashMap as HashMap;
+
+    use crate::{CoreBPE, Rank};
+
+    fn setup_bpe() -> CoreBPE {
+        let mut encoder: HashMap<Vec<u8>, Rank> =
+            (0..=255u8).map(|b| (vec![b], b.into())).collect();
+        for (i, token) in ["ab", "cd", "abcd", " ab"].iter().enumerate() {
+            encoder.insert(token.as_bytes().to_vec(), 256 + i as Rank);
+        }
+        CoreBPE::new_internal(encoder, HashMap::default(), r" ?\w+|\s+(?!\S)|\s+|[^\w\s]+").unwrap()
+    }
+
+    #[test]
+    fn test_chunk_prefers_paragraphs() {
+        let bpe = setup_bpe();
+        let text = "ab ab.\n\nab ab ab. ab\nab ab";
+        let chunks = bpe.chunk(text, 7, 0);
+        let texts: Vec<&str> = chunks.iter().map(|chunk| chunk.text).collect();
+        assert_eq!(texts, vec!["ab ab.\n\n", "ab ab ab. ab\n", "ab ab"]);
+        for chunk in &chunks {
+            assert_eq!(chunk.text, &text[chunk.start..chunk.end]);
+            assert_eq!(chunk.tokens, bpe.encode_ordinary(chunk.text));
+        }
+
+        assert_eq!(bpe.chunk("", 8, 0), vec![]);
+        assert_eq!(bpe.chunk(text, 100, 10).len(), 1);
+    }
+
+    #[test]
+    fn test_chunk_invariants() {
+        let bpe = setup_bpe();
+        let text = "abcd ab cd\n\nabé ab 😀😀😀 ab\nabcdabcdabcdabcd, ab.\n  ab ab";
+        let boundaries = bpe.chunk_boundaries(text);
+        for max_tokens in 1..20 {
+            for overlap in 0..max_tokens {
+                let chunks = bpe.chunk(text, max_tokens, overlap);
+                assert_eq!(chunks[0].start, 0);
+                assert_eq!(chunks.last().unwrap().end, text.len());
+                for chunk in &chunks {
+                    assert_eq!(chunk.text, &text[chunk.start..chunk.end]);
+                    assert_eq!(chunk.tokens, bpe.encode_ordinary(chunk.text));
+                    // Only text between adjacent boundaries can be longer than the budget
+                    assert!(
+                        chunk.tokens.len() <= max_tokens
+                            || !boundaries
+                                .iter()
+                                .any(|b| chunk.start < b.byte && b.byte < chunk.end)
+                    );
+                }
+                for pair in chunks.windows(2) {
+                    assert!(pair[0].start < pair[1].start);
+                    assert!(pair[1].start <= pair[0].end);
+                    if overlap == 0 {
+                        assert_eq!(pair[1].start, pair[0].end);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,395 @@
+use std::collections::HashSet;
+use std::ops::Deref;
+
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::{CoreBPE, Rank};
+
+/// Everything needed to construct an encoding.
+///
+/// This mirrors the keyword arguments of `Encoding` in `tiktoken/core.py`, i.e. what the
+/// constructors in `tiktoken_ext/openai_public.py` return.
+///
+/// With the `serde` feature, mergeable tokens are serialized as base64 strings in human readable
+/// formats (like the `.tiktoken` format) and as raw bytes otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EncodingSpec {
+    /// The name of the encoding. Encodings with different special tokens should have different
+    /// names.
+    pub name: String,
+    /// The regex pattern used to split text before BPE.
+    pub pat_str: String,
+    /// Mergeable token bytes and their ranks. Ranks must correspond to merge priority.
+    #[cfg_attr(feature = "serde", serde(with = "serde_impls::mergeable_ranks"))]
+    pub mergeable_ranks: HashMap<Vec<u8>, Rank>,
+    pub special_tokens: HashMap<String, Rank>,
+    /// If set, the total number of mergeable and special tokens.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub explicit_n_vocab: Option<usize>,
+}
+
+impl EncodingSpec {
+    pub fn to_core_bpe(&self) -> Result<CoreBPE, Box<dyn std::error::Error + Send + Sync>> {
+        CoreBPE::new_internal(
+            self.mergeable_ranks.clone(),
+            self.special_tokens.clone(),
+            &self.pat_str,
+        )
+    }
+
+    pub fn into_core_bpe(self) -> Result<CoreBPE, Box<dyn std::error::Error + Send + Sync>> {
+        CoreBPE::new_internal(self.mergeable_ranks, self.special_tokens, &self.pat_str)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EncodingError {
+    pub message: String,
+}
+
+impl std::fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Could not construct encoding: {}", self.message)
+    }
+}
+
+impl std::error::Error for EncodingError {}
+
+/// A `CoreBPE` together with the metadata of `Encoding` in `tiktoken/core.py`.
+///
+/// `Encoding` derefs to its `CoreBPE`, so all encoding and decoding methods are available on it.
+pub struct Encoding {
+    name: String,
+    max_token_value: Rank,
+    special_token_values: HashSet<Rank>,
+    core_bpe: CoreBPE,
+}
+
+impl Encoding {
+    /// Builds an encoding, checking `explicit_n_vocab` if it is set.
+    pub fn new(spec: EncodingSpec) -> Result<Self, EncodingError> {
+        let error = |message: String| EncodingError { message };
+        let Some(max_mergeable) = spec.mergeable_ranks.values().copied().max() else {
+            return Err(error(format!("{} has no mergeable tokens", spec.name)));
+        };
+        let max_token_value = spec
+            .special_tokens
+            .values()
+            .copied()
+            .fold(max_mergeable, Rank::max);
+        if let Some(explicit_n_vocab) = spec.explicit_n_vocab {
+            let n_tokens = spec.mergeable_ranks.len() + spec.special_tokens.len();
+            if n_tokens != explicit_n_vocab {
+                return Err(error(format!(
+                    "{} has {n_tokens} mergeable and special tokens, expected {explicit_n_vocab}",
+                    spec.name
+                )));
+            }
+            if max_token_value as usize != explicit_n_vocab - 1 {
+                return Err(error(format!(
+                    "{} has max token value {max_token_value}, expected {}",
+                    spec.name,
+                    explicit_n_vocab - 1
+                )));
+            }
+        }
+        let special_token_values = spec.special_tokens.values().copied().collect();
+        let name = spec.name.clone();
+        let core_bpe = spec.into_core_bpe().map_err(|e| error(e.to_string()))?;
+        Ok(Encoding {
+            name,
+            max_token_value,
+            special_token_values,
+            core_bpe,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn core_bpe(&self) -> &CoreBPE {
+        &self.core_bpe
+    }
+
+    /// The largest mergeable or special token.
+    pub fn max_token_value(&self) -> Rank {
+        self.max_token_value
+    }
+
+    /// For consistency with Python. Prefer `max_token_value() + 1`.
+    pub fn n_vocab(&self) -> usize {
+        self.max_token_value as usize + 1
+    }
+
+    /// The `<|endoftext|>` token, if the encoding has one.
+    pub fn eot_token(&self) -> Option<Rank> {
+        self.core_bpe
+            .special_tokens_encoder
+            .get("<|endoftext|>")
+            .copied()
+    }
+
+    pub fn special_tokens_set(&self) -> HashSet<&str> {
+        self.core_bpe.special_tokens()
+    }
+
+    pub fn is_special_token(&self, token: Rank) -> bool {
+        self.special_token_values.contains(&token)
+    }
+}
+
+impl Deref for Encoding {
+    type Target = CoreBPE;
+
+    fn deref(&self) -> &CoreBPE {
+        &self.core_bpe
+    }
+}
+
+impl TryFrom<EncodingSpec> for Encoding {
+    type Error = EncodingError;
+
+    fn try_from(spec: EncodingSpec) -> Result<Self, EncodingError> {
+        Encoding::new(spec)
+    }
+}
+
+impl std::fmt::Debug for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<Encoding {:?}>", self.name)
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde_impls {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use rustc_hash::FxHashMap as HashMap;
+    use serde::de::{Error, Visitor};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::{CoreBPE, Rank};
+
+    #[derive(PartialEq, Eq, Hash)]
+    struct TokenBytes(Vec<u8>);
+
+    struct TokenBytesRef<'a>(&'a [u8]);
+
+    impl Serialize for TokenBytesRef<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            if serializer.is_human_readable() {
+                serializer.serialize_str(&BASE64.encode(self.0))
+            } else {
+                serializer.serialize_bytes(self.0)
+            }
+        }
+    }
+
+    impl<'de> Deserialize<'de> for TokenBytes {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct TokenBytesVisitor;
+
+            impl<'de> Visitor<'de> for TokenBytesVisitor {
+                type Value = TokenBytes;
+
+                fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    write!(f, "token bytes, or a base64 string of them")
+                }
+
+                fn visit_str<E: Error>(self, v: &str) -> Result<TokenBytes, E> {
+                    BASE64.decode(v).map(TokenBytes).map_err(E::custom)
+                }
+
+                fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<TokenBytes, E> {
+                    Ok(TokenBytes(v.to_vec()))
+                }
+
+                fn visit_byte_buf<E: Error>(self, v: Vec<u8>) -> Result<TokenBytes, E> {
+                    Ok(TokenBytes(v))
+                }
+
+                fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                    self,
+                    mut seq: A,
+                ) -> Result<TokenBytes, A::Error> {
+                    let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                    while let Some(b) = seq.next_element()? {
+                        bytes.push(b);
+                    }
+                    Ok(TokenBytes(bytes))
+                }
+            }
+
+            if deserializer.is_human_readable() {
+                deserializer.deserialize_str(TokenBytesVisitor)
+            } else {
+                deserializer.deserialize_byte_buf(TokenBytesVisitor)
+            }
+        }
+    }
+
+    pub(super) mod mergeable_ranks {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            ranks: &HashMap<Vec<u8>, Rank>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            // Sort so that the output is deterministic
+            let mut sorted: Vec<(&Vec<u8>, &Rank)> = ranks.iter().collect();
+            sorted.sort_by_key(|&(_, rank)| *rank);
+            serializer.collect_map(
+                sorted
+                    .into_iter()
+                    .map(|(token, rank)| (TokenBytesRef(token), rank)),
+            )
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<HashMap<Vec<u8>, Rank>, D::Error> {
+            let ranks = HashMap::<TokenBytes, Rank>::deserialize(deserializer)?;
+            Ok(ranks
+                .into_iter()
+                .map(|(token, rank)| (token.0, rank))
+                .collect())
+        }
+    }
+
+    /// The state `CoreBPE` is serialized as. Like `__getstate__` in `tiktoken/core.py`, this is
+    /// just what is needed to rebuild the regexes and derived tables.
+    #[derive(Serialize)]
+    struct CoreBPEStateRef<'a> {
+        pat_str: &'a str,
+        #[serde(with = "mergeable_ranks")]
+        mergeable_ranks: &'a HashMap<Vec<u8>, Rank>,
+        special_tokens: &'a HashMap<String, Rank>,
+    }
+
+    #[derive(Deserialize)]
+    struct CoreBPEState {
+        pat_str: String,
+        #[serde(with = "mergeable_ranks")]
+        mergeable_ranks: HashMap<Vec<u8>, Rank>,
+        special_tokens: HashMap<String, Rank>,
+    }
+
+    impl Serialize for CoreBPE {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            CoreBPEStateRef {
+                pat_str: self.pattern(),
+                mergeable_ranks: &self.mergeable_ranks(),
+                special_tokens: &self.special_tokens_encoder,
+            }
+            .serialize(serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for CoreBPE {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let state = CoreBPEState::deserialize(deserializer)?;
+            CoreBPE::new_internal(state.mergeable_ranks, state.special_tokens, &state.pat_str)
+                .map_err(D::Error::custom)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup_spec() -> EncodingSpec {
+        let mut mergeable_ranks: HashMap<Vec<u8>, Rank> =
+            (0..=255u8).map(|b| (vec![b], b.into())).collect();
+        mergeable_ranks.insert(b"ab".to_vec(), 256);
+        mergeable_ranks.insert(b"\xff\xfe".to_vec(), 257);
+        EncodingSpec {
+            name: "test".to_string(),
+            pat_str: r"\w+|\s+|[^\w\s]+".to_string(),
+            mergeable_ranks,
+            special_tokens: HashMap::from_iter([("<|endoftext|>".to_string(), 258)]),
+            explicit_n_vocab: Some(259),
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        let enc = Encoding::new(setup_spec()).unwrap();
+        assert_eq!(enc.name(), "test");
+        assert_eq!(format!("{enc:?}"), r#"<Encoding "test">"#);
+        assert_eq!(enc.max_token_value(), 258);
+        assert_eq!(enc.n_vocab(), 259);
+        assert_eq!(enc.eot_token(), Some(258));
+        assert_eq!(enc.special_tokens_set(), HashSet::from(["<|endoftext|>"]));
+        assert!(enc.is_special_token(258));
+        assert!(!enc.is_special_token(256));
+        assert_eq!(enc.encode_ordinary("ab"), vec![256]);
+    }
+
+    #[test]
+    fn test_encoding_explicit_n_vocab() {
+        let mut spec = setup_spec();
+        spec.explicit_n_vocab = Some(260);
+        let err = Encoding::new(spec.clone()).unwrap_err();
+        assert_eq!(
+            err.message,
+            "test has 259 mergeable and special tokens, expected 260"
+        );
+
+        // Right number of tokens, but with a gap
+        spec.special_tokens.insert("<|endoftext|>".to_string(), 300);
+        spec.explicit_n_vocab = Some(259);
+        let err = Encoding::new(spec.clone()).unwrap_err();
+        assert_eq!(err.message, "test has max token value 300, expected 258");
+
+        spec.explicit_n_vocab = None;
+        assert_eq!(Encoding::new(spec).unwrap().n_vocab(), 301);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_spec_serde_roundtrip() {
+        let spec = setup_spec();
+        let json = serde_json::to_string(&spec).unwrap();
+        assert!(json.contains(r#""YWI=":256"#));
+        assert!(json.contains(r#""//4=":257"#));
+        assert_eq!(serde_json::from_str::<EncodingSpec>(&json).unwrap(), spec);
+        // Output is deterministic
+        assert_eq!(serde_json::to_string(&spec.clone()).unwrap(), json);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_core_bpe_serde_roundtrip() {
+        let bpe = setup_spec().into_core_bpe().unwrap();
+        let json = serde_json::to_string(&bpe).unwrap();
+        let loaded: CoreBPE = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.mergeable_ranks(), bpe.mergeable_ranks());
+        assert!(loaded.vocab.sorted().eq(bpe.vocab.sorted()));
+        assert_eq!(loaded.special_tokens_encoder, bpe.special_tokens_encoder);
+        assert_eq!(
+            loaded.encode_with_special_tokens("ab <|endoftext|>"),
+            vec![256, 32, 258]
+        );
+
+        let bad_pattern = json.replace(r#"\\w+|"#, r#"(\\w+|"#);
+        let err = serde_json::from_str::<CoreBPE>(&bad_pattern).err().unwrap();
+        assert!(err.to_string().contains("parenthesis"), "{err}");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_binary_roundtrip() {
+        let spec = setup_spec();
+        let data = bincode::serialize(&spec).unwrap();
+        assert_eq!(bincode::deserialize::<EncodingSpec>(&data).unwrap(), spec);
+
+        let bpe = spec.into_core_bpe().unwrap();
+        let data = bincode::serialize(&bpe).unwrap();
+        let loaded: CoreBPE = bincode::deserialize(&data).unwrap();
+        assert_eq!(loaded.mergeable_ranks(), bpe.mergeable_ranks());
+        assert_eq!(loaded.pattern(), bpe.pattern());
+    }
+}
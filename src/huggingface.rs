@@ -0,0 +1,838 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+
+use rustc_hash::FxHashMap as HashMap;
+use serde_json::{Value, json};
+
+use crate::encoding::EncodingSpec;
+use crate::load::{bpe_merges, data_gym_byte_to_char, encode_data_gym};
+use crate::{CoreBPE, Rank};
+
+/// The pattern `tokenizers` splits on for a `ByteLevel` pre-tokenizer with `use_regex: true`.
+/// This is the pattern from the original GPT-2 release.
+pub const BYTE_LEVEL_PATTERN: &str =
+    r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+#[derive(Debug, Clone)]
+pub struct HuggingFaceError {
+    pub message: String,
+}
+
+impl std::fmt::Display for HuggingFaceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Could not convert tokenizer.json: {}", self.message)
+    }
+}
+
+impl std::error::Error for HuggingFaceError {}
+
+fn error(message: String) -> HuggingFaceError {
+    HuggingFaceError { message }
+}
+
+fn type_of(value: &Value) -> &str {
+    value["type"].as_str().unwrap_or("<missing type>")
+}
+
+/// Reads a HuggingFace `tokenizer.json` describing a byte-level BPE model.
+///
+/// Only the subset of `tokenizers` that maps exactly onto tiktoken is supported: no normalizer,
+/// a `ByteLevel` pre-tokenizer (optionally preceded by an isolating regex `Split`, as used by
+/// e.g. Llama 3), a `BPE` model whose token ids increase with merge priority, and a `ByteLevel`
+/// decoder. Anything else is an error rather than a silently different tokenisation.
+///
+/// All `added_tokens` become special tokens, so the ids of `tokenizer.encode(text,
+/// add_special_tokens=False)` match [`CoreBPE::encode_with_special_tokens`]. The post-processor
+/// is not applied. Also note that `tokenizers` keeps text between regex matches as separate
+/// pieces, whereas tiktoken drops it; the patterns in use in practice match all text.
+pub fn load_tokenizer_json<R: Read>(
+    name: &str,
+    reader: R,
+) -> Result<EncodingSpec, HuggingFaceError> {
+    let tokenizer: Value =
+        serde_json::from_reader(reader).map_err(|e| error(format!("invalid JSON: {e}")))?;
+
+    let normalizer = &tokenizer["normalizer"];
+    if !normalizer.is_null() {
+        return Err(error(format!(
+            "unsupported normalizer {}",
+            type_of(normalizer)
+        )));
+    }
+    let decoder = &tokenizer["decoder"];
+    if !decoder.is_null() && type_of(decoder) != "ByteLevel" {
+        return Err(error(format!("unsupported decoder {}", type_of(decoder))));
+    }
+
+    let pat_str = pattern_from_pre_tokenizer(&tokenizer["pre_tokenizer"])?;
+    let special_tokens = special_tokens_from_added_tokens(&tokenizer["added_tokens"])?;
+    let mergeable_ranks = mergeable_ranks_from_model(&tokenizer["model"], &special_tokens)?;
+
+    Ok(EncodingSpec {
+        name: name.to_string(),
+        pat_str,
+        mergeable_ranks,
+        special_tokens,
+        explicit_n_vocab: None,
+    })
+}
+
+/// Reads a HuggingFace `tokenizer.json` from disk. See [`load_tokenizer_json`].
+pub fn load_tokenizer_json_file(
+    name: &str,
+    path: impl AsRef<Path>,
+) -> Result<EncodingSpec, Box<dyn std::error::Error + Send + Sync>> {
+    let file = BufReader::new(File::open(path)?);
+    Ok(load_tokenizer_json(name, file)?)
+}
+
+fn check_byte_level(byte_level: &Value) -> Result<(), HuggingFaceError> {
+    // add_prefix_space defaults to true in `tokenizers`
+    if byte_level["add_prefix_space"].as_bool() != Some(false) {
+        return Err(error(
+            "ByteLevel pre-tokenizer with add_prefix_space is not supported".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn pattern_from_pre_tokenizer(pre_tokenizer: &Value) -> Result<String, HuggingFaceError> {
+    let steps: Vec<&Value> = match type_of(pre_tokenizer) {
+        "Sequence" => pre_tokenizer["pretokenizers"]
+            .as_array()
+            .map(|steps| steps.iter().collect())
+            .unwrap_or_default(),
+        _ => vec![pre_tokenizer],
+    };
+    let unsupported = || {
+        let types: Vec<&str> = steps.iter().map(|step| type_of(step)).collect();
+        error(format!("unsupported pre_tokenizer {types:?}"))
+    };
+
+    match steps.as_slice() {
+        [byte_level] if type_of(byte_level) == "ByteLevel" => {
+            check_byte_level(byte_level)?;
+            if byte_level["use_regex"].as_bool().unwrap_or(true) {
+                Ok(BYTE_LEVEL_PATTERN.to_string())
+            } else {
+                // No splitting at all, the whole text is a single piece
+                Ok(r"[\s\S]+".to_string())
+            }
+        }
+        [split, byte_level] if type_of(split) == "Split" && type_of(byte_level) == "ByteLevel" => {
+            check_byte_level(byte_level)?;
+            if byte_level["use_regex"].as_bool().unwrap_or(true) {
+                return Err(error(
+                    "Split followed by a ByteLevel pre-tokenizer with use_regex is not supported"
+                        .to_string(),
+                ));
+            }
+            if split["behavior"].as_str() != Some("Isolated")
+                || split["invert"].as_bool().unwrap_or(false)
+            {
+                return Err(error(format!(
+                    "unsupported Split behavior {} (invert: {})",
+                    split["behavior"], split["invert"]
+                )));
+            }
+            let pattern = &split["pattern"];
+            if let Some(regex) = pattern["Regex"].as_str() {
+                Ok(pattern_from_tokenizers(regex))
+            } else if let Some(string) = pattern["String"].as_str() {
+                Ok(fancy_regex::escape(string).into_owned())
+            } else {
+                Err(error(format!("unsupported Split pattern {pattern}")))
+            }
+        }
+        _ => Err(unsupported()),
+    }
+}
+
+fn special_tokens_from_added_tokens(
+    added_tokens: &Value,
+) -> Result<HashMap<String, Rank>, HuggingFaceError> {
+    let mut special_tokens = HashMap::default();
+    for added_token in added_tokens
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+    {
+        let (Some(content), Some(id)) =
+            (added_token["content"].as_str(), added_token["id"].as_u64())
+        else {
+            return Err(error(format!("malformed added token {added_token}")));
+        };
+        for option in ["lstrip", "rstrip", "single_word"] {
+            if added_token[option].as_bool() == Some(true) {
+                return Err(error(format!(
+                    "added token {content:?} uses unsupported option {option}"
+                )));
+            }
+        }
+        let id = Rank::try_from(id).map_err(|_| error(format!("token id {id} is too large")))?;
+        if special_tokens.insert(content.to_string(), id).is_some() {
+            return Err(error(format!("duplicate added token {content:?}")));
+        }
+    }
+    Ok(special_tokens)
+}
+
+fn mergeable_ranks_from_model(
+    model: &Value,
+    special_tokens: &HashMap<String, Rank>,
+) -> Result<HashMap<Vec<u8>, Rank>, HuggingFaceError> {
+    if model["type"].as_str().is_some_and(|t| t != "BPE") {
+        return Err(error(format!("unsupported model {}", type_of(model))));
+    }
+    if !model["dropout"].is_null() {
+        return Err(error("BPE dropout is not supported".to_string()));
+    }
+    for option in ["continuing_subword_prefix", "end_of_word_suffix"] {
+        if model[option].as_str().is_some_and(|s| !s.is_empty()) {
+            return Err(error(format!("BPE {option} is not supported")));
+        }
+    }
+
+    let Some(vocab) = model["vocab"].as_object() else {
+        return Err(error("missing model.vocab".to_string()));
+    };
+    let special_ids: HashMap<Rank, &str> = special_tokens
+        .iter()
+        .map(|(token, &id)| (id, token.as_str()))
+        .collect();
+
+    let mut char_to_byte: HashMap<char, u8> = HashMap::default();
+    for (b, c) in data_gym_byte_to_char().into_iter().enumerate() {
+        char_to_byte.insert(c, b as u8);
+    }
+    let decode_byte_level = |token: &str| -> Result<Vec<u8>, HuggingFaceError> {
+        token
+            .chars()
+            .map(|c| char_to_byte.get(&c).copied())
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| error(format!("token {token:?} is not byte-level encoded")))
+    };
+
+    let mut mergeable_ranks: HashMap<Vec<u8>, Rank> = HashMap::default();
+    let mut seen_ids: HashMap<Rank, &str> = HashMap::default();
+    for (token, id) in vocab {
+        let id = id
+            .as_u64()
+            .and_then(|id| Rank::try_from(id).ok())
+            .ok_or_else(|| error(format!("invalid id {id} for token {token:?}")))?;
+        if let Some(other) = seen_ids.insert(id, token) {
+            return Err(error(format!(
+                "tokens {other:?} and {token:?} share the id {id}"
+            )));
+        }
+        if let Some(&special) = special_ids.get(&id) {
+            if special != token {
+                return Err(error(format!(
+                    "added token {special:?} has the same id as {token:?}"
+                )));
+            }
+            continue;
+        }
+        mergeable_ranks.insert(decode_byte_level(token)?, id);
+    }
+    if let Some(b) = (0..=255u8).find(|&b| !mergeable_ranks.contains_key([b].as_slice())) {
+        return Err(error(format!("missing single byte token {b:#04x}")));
+    }
+
+    // tiktoken merges whichever adjacent pair makes the lowest ranked token, so the ids must
+    // order tokens the same way as the merges list does
+    let mut hf_merges: HashMap<Vec<u8>, usize> = HashMap::default();
+    let mut last_id = None;
+    for merge in model["merges"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+    {
+        let pair = match merge {
+            Value::String(merge) => merge.split_once(' '),
+            Value::Array(pair) if pair.len() == 2 => pair[0].as_str().zip(pair[1].as_str()),
+            _ => None,
+        };
+        let Some((first, second)) = pair else {
+            return Err(error(format!("malformed merge {merge}")));
+        };
+        let first = decode_byte_level(first)?;
+        let mut token = first.clone();
+        token.extend(decode_byte_level(second)?);
+        let Some(&id) = mergeable_ranks.get(&token) else {
+            return Err(error(format!(
+                "merge {merge} produces a token that is not mergeable"
+            )));
+        };
+        if last_id.is_some_and(|last_id| id <= last_id) {
+            return Err(error(format!(
+                "merge {merge} produces id {id}, but ids must increase with merge priority"
+            )));
+        }
+        last_id = Some(id);
+        hf_merges.insert(token, first.len());
+    }
+
+    let derived = bpe_merges(&mergeable_ranks).map_err(error)?;
+    for (first, second, _) in derived {
+        let token = [first, second].concat();
+        if hf_merges.get(&token) != Some(&first.len()) {
+            return Err(error(format!(
+                "token {:?} is not produced by the merge tiktoken would use",
+                bstr::BStr::new(&token)
+            )));
+        }
+    }
+
+    Ok(mergeable_ranks)
+}
+
+/// Rewrites a tiktoken split pattern into one with the same meaning for `tokenizers`.
+///
+/// `tokenizers` uses Oniguruma with Ruby syntax, which mostly agrees with `fancy_regex`, including
+/// on possessive `*+`, `++` and `?+`. The constructs that have to be rewritten are:
+/// - `$`, which in Ruby syntax matches at the end of every line rather than only at the end of
+///   the text. It becomes `\z`. For instance, `\s++$` in `r50k_pat_str`.
+/// - Possessive intervals such as `{1,3}+`, which in Ruby syntax mean "repeat the interval one or
+///   more times". They become atomic groups, e.g. `\p{N}{1,3}+` in `cl100k_base` becomes
+///   `(?>\p{N}{1,3})`.
+///
+/// Ports of `tokenizers` to other regex engines (e.g. JavaScript) support neither possessive
+/// quantifiers nor atomic groups and need further rewriting by the consumer.
+pub fn pattern_for_tokenizers(pattern: &str) -> String {
+    let mut out = String::with_capacity(pattern.len());
+    // Where the most recent atom starts in `out`, so that a quantifier can wrap it
+    let mut atom_start = 0;
+    let mut group_starts = Vec::new();
+    let mut class_depth = 0;
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if class_depth == 0 {
+                atom_start = out.len();
+            }
+            out.push(c);
+            if let Some(escaped) = chars.next() {
+                out.push(escaped);
+                // \p{L} and friends
+                if matches!(escaped, 'p' | 'P' | 'x' | 'u') && chars.peek() == Some(&'{') {
+                    for c in chars.by_ref() {
+                        out.push(c);
+                        if c == '}' {
+                            break;
+                        }
+                    }
+                }
+            }
+            continue;
+        }
+        if class_depth > 0 {
+            match c {
+                '[' => class_depth += 1,
+                ']' => class_depth -= 1,
+                _ => {}
+            }
+            out.push(c);
+            continue;
+        }
+        match c {
+            '[' => {
+                atom_start = out.len();
+                class_depth = 1;
+                out.push(c);
+                // A leading `]` (possibly after `^`) is a literal
+                if chars.peek() == Some(&'^') {
+                    out.push(chars.next().unwrap());
+                }
+                if chars.peek() == Some(&']') {
+                    out.push(chars.next().unwrap());
+                }
+            }
+            '(' => {
+                // The inverse of what `pattern_from_tokenizers` does with a Ruby `$`
+                if chars.clone().take(5).eq("?m:$)".chars()) {
+                    chars.nth(4);
+                    atom_start = out.len();
+                    out.push('$');
+                    continue;
+                }
+                group_starts.push(out.len());
+                out.push(c);
+            }
+            ')' => {
+                atom_start = group_starts.pop().unwrap_or(0);
+                out.push(c);
+            }
+            '$' => {
+                atom_start = out.len();
+                out.push_str("\\z");
+            }
+            '{' => {
+                let mut interval = String::from(c);
+                for c in chars.by_ref() {
+                    interval.push(c);
+                    if c == '}' {
+                        break;
+                    }
+                }
+                if chars.peek() == Some(&'+') {
+                    chars.next();
+                    out.insert_str(atom_start, "(?>");
+                    out.push_str(&interval);
+                    out.push(')');
+                } else {
+                    out.push_str(&interval);
+                }
+            }
+            _ => {
+                if !matches!(c, '|' | '*' | '+' | '?') {
+                    atom_start = out.len();
+                }
+                out.push(c);
+            }
+        }
+    }
+    out
+}
+
+/// Rewrites a `tokenizers` split pattern into one with the same meaning for tiktoken.
+///
+/// This is the inverse of [`pattern_for_tokenizers`], so patterns survive a round trip through
+/// `tokenizer.json` unchanged:
+/// - `\z` becomes `$`, and a Ruby `$`, which matches at the end of every line, becomes `(?m:$)`.
+/// - An atomic group around a single repeated atom, e.g. `(?>\p{N}{1,3})`, becomes a possessive
+///   interval, `\p{N}{1,3}+`.
+/// - A Ruby interval followed by `+`, e.g. `a{2}+`, means "repeat the interval one or more
+///   times", and becomes `(?:a{2})+`.
+pub fn pattern_from_tokenizers(pattern: &str) -> String {
+    let mut out = String::with_capacity(pattern.len());
+    // Where the most recent atom starts in `out`, so that a quantifier can wrap it
+    let mut atom_start = 0;
+    // Where each open group starts in `out`, and whether it is atomic
+    let mut group_starts: Vec<(usize, bool)> = Vec::new();
+    // The start of the atom of the last interval and the end of the interval
+    let mut last_interval = None;
+    let mut class_depth = 0;
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if class_depth > 0 {
+                out.push(c);
+                out.extend(chars.next());
+                continue;
+            }
+            atom_start = out.len();
+            match chars.next() {
+                Some('z') => out.push('$'),
+                Some(escaped) => {
+                    out.push(c);
+                    out.push(escaped);
+                    // \p{L} and friends
+                    if matches!(escaped, 'p' | 'P' | 'x' | 'u') && chars.peek() == Some(&'{') {
+                        for c in chars.by_ref() {
+                            out.push(c);
+                            if c == '}' {
+                                break;
+                            }
+                        }
+                    }
+                }
+                None => out.push(c),
+            }
+            continue;
+        }
+        if class_depth > 0 {
+            match c {
+                '[' => class_depth += 1,
+                ']' => class_depth -= 1,
+                _ => {}
+            }
+            out.push(c);
+            continue;
+        }
+        match c {
+            '[' => {
+                atom_start = out.len();
+                class_depth = 1;
+                out.push(c);
+                // A leading `]` (possibly after `^`) is a literal
+                if chars.peek() == Some(&'^') {
+                    out.push(chars.next().unwrap());
+                }
+                if chars.peek() == Some(&']') {
+                    out.push(chars.next().unwrap());
+                }
+            }
+            '(' => {
+                let atomic = chars.peek() == Some(&'?') && {
+                    let mut lookahead = chars.clone();
+                    lookahead.next();
+                    lookahead.next() == Some('>')
+                };
+                group_starts.push((out.len(), atomic));
+                if atomic {
+                    chars.next();
+                    chars.next();
+                    out.push_str("(?>");
+                } else {
+                    out.push(c);
+                }
+            }
+            ')' => {
+                let (start, atomic) = group_starts.pop().unwrap_or((0, false));
+                atom_start = start;
+                if atomic && last_interval == Some((start + 3, out.len())) {
+                    out.replace_range(start..start + 3, "");
+                    out.push('+');
+                } else {
+                    out.push(c);
+                }
+            }
+            '$' => {
+                atom_start = out.len();
+                out.push_str("(?m:$)");
+            }
+            '{' => {
+                let mut interval = String::from(c);
+                for c in chars.by_ref() {
+                    interval.push(c);
+                    if c == '}' {
+                        break;
+                    }
+                }
+                if chars.peek() == Some(&'+') {
+                    chars.next();
+                    out.insert_str(atom_start, "(?:");
+                    out.push_str(&interval);
+                    out.push_str(")+");
+                } else {
+                    out.push_str(&interval);
+                    last_interval = Some((atom_start, out.len()));
+                }
+            }
+            _ => {
+                if !matches!(c, '|' | '*' | '+' | '?') {
+                    atom_start = out.len();
+                }
+                out.push(c);
+            }
+        }
+    }
+    out
+}
+
+/// Writes `bpe` as a HuggingFace `tokenizer.json`.
+///
+/// Token ids are tiktoken ranks, and the merges list is recovered from the ranks (so ranks must
+/// correspond to merge priority). The split pattern goes through [`pattern_for_tokenizers`] and
+/// becomes an isolating `Split` before a `ByteLevel` pre-tokenizer that does no splitting of its
+/// own. `ignore_merges` is set, since tiktoken maps a piece that is itself a token straight to
+/// that token. Special tokens become special `added_tokens`. Ids in `added_tokens` must be
+/// unique, so special tokens that share a rank (as in o200k_harmony) are an error.
+pub fn dump_tokenizer_json<W: Write>(bpe: &CoreBPE, writer: W) -> Result<(), HuggingFaceError> {
+    let byte_to_char = data_gym_byte_to_char();
+
+    let mergeable_ranks = bpe.mergeable_ranks();
+    let vocab: serde_json::Map<String, Value> = mergeable_ranks
+        .iter()
+        .map(|(token, &rank)| (encode_data_gym(&byte_to_char, token), json!(rank)))
+        .collect();
+    let merges: Vec<String> = bpe_merges(&mergeable_ranks)
+        .map_err(error)?
+        .into_iter()
+        .map(|(first, second, _)| {
+            format!(
+                "{} {}",
+                encode_data_gym(&byte_to_char, first),
+                encode_data_gym(&byte_to_char, second)
+            )
+        })
+        .collect();
+
+    let mut special_tokens: Vec<(&String, &Rank)> = bpe.special_tokens_encoder.iter().collect();
+    special_tokens.sort_by_key(|&(content, rank)| (*rank, content));
+    let shared: Vec<String> = special_tokens
+        .chunk_by(|(_, a), (_, b)| a == b)
+        .filter(|tokens| tokens.len() > 1)
+        .map(|tokens| {
+            let contents: Vec<&String> = tokens.iter().map(|&(content, _)| content).collect();
+            format!("{contents:?} share rank {}", tokens[0].1)
+        })
+        .collect();
+    if !shared.is_empty() {
+        return Err(error(format!(
+            "special tokens {}, but ids in added_tokens must be unique",
+            shared.join("; ")
+        )));
+    }
+    let added_tokens: Vec<Value> = special_tokens
+        .into_iter()
+        .map(|(content, rank)| {
+            json!({
+                "id": rank,
+                "content": content,
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": true,
+            })
+        })
+        .collect();
+
+    let tokenizer = json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": added_tokens,
+        "normalizer": null,
+        "pre_tokenizer": {
+            "type": "Sequence",
+            "pretokenizers": [
+                {
+                    "type": "Split",
+                    "pattern": {"Regex": pattern_for_tokenizers(bpe.pattern())},
+                    "behavior": "Isolated",
+                    "invert": false,
+                },
+                {
+                    "type": "ByteLevel",
+                    "add_prefix_space": false,
+                    "trim_offsets": true,
+                    "use_regex": false,
+                },
+            ],
+        },
+        "post_processor": null,
+        "decoder": {
+            "type": "ByteLevel",
+            "add_prefix_space": true,
+            "trim_offsets": true,
+            "use_regex": true,
+        },
+        "model": {
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": null,
+            "continuing_subword_prefix": null,
+            "end_of_word_suffix": null,
+            "fuse_unk": false,
+            "byte_fallback": false,
+            "ignore_merges": true,
+            "vocab": vocab,
+            "merges": merges,
+        },
+    });
+    serde_json::to_writer(writer, &tokenizer).map_err(|e| error(format!("could not write: {e}")))
+}
+
+impl CoreBPE {
+    /// Writes this encoding as a HuggingFace `tokenizer.json`. See
+    /// [`huggingface::dump_tokenizer_json`](dump_tokenizer_json).
+    pub fn to_tokenizer_json<W: Write>(&self, writer: W) -> Result<(), HuggingFaceError> {
+        dump_tokenizer_json(self, writer)
+    }
+
+    /// Creates a `CoreBPE` from a HuggingFace `tokenizer.json`. See
+    /// [`huggingface::load_tokenizer_json`](load_tokenizer_json).
+    pub fn from_tokenizer_json<R: Read>(
+        reader: R,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        load_tokenizer_json("", reader)?.into_core_bpe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::openai_public::{CL100K_PAT_STR, O200K_PAT_STR, R50K_PAT_STR};
+
+    fn setup_tokenizer(pre_tokenizer: Value) -> Value {
+        let byte_to_char = data_gym_byte_to_char();
+        let mut vocab = serde_json::Map::new();
+        for b in 0..=255u8 {
+            vocab.insert(byte_to_char[b as usize].to_string(), json!(b));
+        }
+        let merges = ["a b", "Ġ a", "Ġ ab", "Ġ Ġ"];
+        for merge in merges {
+            vocab.insert(merge.replace(' ', ""), json!(vocab.len()));
+        }
+        vocab.insert("<|endoftext|>".to_string(), json!(vocab.len()));
+        json!({
+            "version": "1.0",
+            "added_tokens": [
+                {"id": 260, "content": "<|endoftext|>", "single_word": false, "lstrip": false,
+                 "rstrip": false, "normalized": false, "special": true},
+            ],
+            "normalizer": null,
+            "pre_tokenizer": pre_tokenizer,
+            "post_processor": {"type": "ByteLevel", "add_prefix_space": true,
+                               "trim_offsets": false, "use_regex": true},
+            "decoder": {"type": "ByteLevel", "add_prefix_space": true, "trim_offsets": true,
+                        "use_regex": true},
+            "model": {"type": "BPE", "dropout": null, "unk_token": null,
+                      "continuing_subword_prefix": "", "end_of_word_suffix": "",
+                      "fuse_unk": false, "byte_fallback": false, "vocab": vocab,
+                      "merges": merges},
+        })
+    }
+
+    fn byte_level() -> Value {
+        json!({"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true,
+               "use_regex": true})
+    }
+
+    fn load(tokenizer: &Value) -> Result<EncodingSpec, HuggingFaceError> {
+        load_tokenizer_json("test", tokenizer.to_string().as_bytes())
+    }
+
+    #[test]
+    fn test_load_tokenizer_json() {
+        let spec = load(&setup_tokenizer(byte_level())).unwrap();
+        assert_eq!(spec.pat_str, BYTE_LEVEL_PATTERN);
+        assert_eq!(spec.mergeable_ranks.len(), 260);
+        assert_eq!(spec.mergeable_ranks[b" ab".as_slice()], 258);
+        assert_eq!(spec.special_tokens["<|endoftext|>"], 260);
+
+        let bpe = spec.into_core_bpe().unwrap();
+        assert_eq!(
+            bpe.encode_with_special_tokens("ab ab  a<|endoftext|>"),
+            vec![256, 258, 32, 257, 260]
+        );
+    }
+
+    #[test]
+    fn test_load_tokenizer_json_split() {
+        let pre_tokenizer = json!({"type": "Sequence", "pretokenizers": [
+            {"type": "Split", "pattern": {"Regex": r"\s+|\S+"}, "behavior": "Isolated",
+             "invert": false},
+            {"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true,
+             "use_regex": false},
+        ]});
+        let spec = load(&setup_tokenizer(pre_tokenizer)).unwrap();
+        assert_eq!(spec.pat_str, r"\s+|\S+");
+        let bpe = spec.into_core_bpe().unwrap();
+        assert_eq!(bpe.encode_ordinary("ab ab"), vec![256, 32, 256]);
+    }
+
+    #[test]
+    fn test_load_tokenizer_json_unsupported() {
+        let mut tokenizer = setup_tokenizer(byte_level());
+        tokenizer["normalizer"] = json!({"type": "NFC"});
+        let err = load(&tokenizer).unwrap_err();
+        assert_eq!(err.message, "unsupported normalizer NFC");
+
+        let mut tokenizer = setup_tokenizer(byte_level());
+        tokenizer["decoder"] = json!({"type": "WordPiece", "prefix": "##", "cleanup": true});
+        let err = load(&tokenizer).unwrap_err();
+        assert_eq!(err.message, "unsupported decoder WordPiece");
+
+        let mut tokenizer = setup_tokenizer(byte_level());
+        tokenizer["pre_tokenizer"]["add_prefix_space"] = json!(true);
+        assert!(load(&tokenizer).is_err());
+
+        let mut tokenizer = setup_tokenizer(byte_level());
+        tokenizer["model"]["merges"] = json!(["Ġ a", "a b", "Ġ ab", "Ġ Ġ"]);
+        let err = load(&tokenizer).unwrap_err();
+        assert!(err.message.contains("ids must increase"), "{err}");
+    }
+
+    #[test]
+    fn test_pattern_for_tokenizers() {
+        let r50k =
+            r"'(?:[sdmt]|ll|ve|re)| ?\p{L}++| ?\p{N}++| ?[^\s\p{L}\p{N}]++|\s++$|\s+(?!\S)|\s";
+        assert_eq!(
+            pattern_for_tokenizers(r50k),
+            r"'(?:[sdmt]|ll|ve|re)| ?\p{L}++| ?\p{N}++| ?[^\s\p{L}\p{N}]++|\s++\z|\s+(?!\S)|\s"
+        );
+        assert_eq!(
+            pattern_for_tokenizers(r"[^\r\n\p{L}\p{N}]?+\p{L}++|\p{N}{1,3}+|[$]|\$|(ab){2}+"),
+            r"[^\r\n\p{L}\p{N}]?+\p{L}++|(?>\p{N}{1,3})|[$]|\$|(?>(ab){2})"
+        );
+        assert_eq!(
+            pattern_for_tokenizers(BYTE_LEVEL_PATTERN),
+            BYTE_LEVEL_PATTERN
+        );
+    }
+
+    #[test]
+    fn test_pattern_from_tokenizers() {
+        assert_eq!(
+            pattern_from_tokenizers(r"\s++\z|(?>\p{N}{1,3})|(?>a{2}b)|a{2}+|\s+$|[$\z]"),
+            r"\s++$|\p{N}{1,3}+|(?>a{2}b)|(?:a{2})+|\s+(?m:$)|[$\z]"
+        );
+        for pattern in [
+            R50K_PAT_STR,
+            CL100K_PAT_STR,
+            O200K_PAT_STR,
+            BYTE_LEVEL_PATTERN,
+            r"\s+(?m:$)|(?:a{2})+|(ab){2}+",
+        ] {
+            assert_eq!(
+                pattern_from_tokenizers(&pattern_for_tokenizers(pattern)),
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn test_dump_tokenizer_json_roundtrip_pattern() {
+        let spec = load(&setup_tokenizer(byte_level())).unwrap();
+        for pattern in [R50K_PAT_STR, CL100K_PAT_STR, O200K_PAT_STR] {
+            let bpe = CoreBPE::new_internal(
+                spec.mergeable_ranks.clone(),
+                spec.special_tokens.clone(),
+                pattern,
+            )
+            .unwrap();
+            let mut buf = Vec::new();
+            bpe.to_tokenizer_json(&mut buf).unwrap();
+            let imported = CoreBPE::from_tokenizer_json(buf.as_slice()).unwrap();
+            assert_eq!(imported.pattern(), pattern);
+        }
+    }
+
+    #[test]
+    fn test_dump_tokenizer_json_roundtrip() {
+        let bpe = load(&setup_tokenizer(byte_level()))
+            .unwrap()
+            .into_core_bpe()
+            .unwrap();
+        let mut buf = Vec::new();
+        bpe.to_tokenizer_json(&mut buf).unwrap();
+
+        let tokenizer: Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(
+            tokenizer["model"]["merges"],
+            json!(["a b", "Ġ a", "Ġ ab", "Ġ Ġ"])
+        );
+        assert_eq!(tokenizer["added_tokens"][0]["content"], "<|endoftext|>");
+
+        let spec = load_tokenizer_json("test", buf.as_slice()).unwrap();
+        assert_eq!(spec.pat_str, BYTE_LEVEL_PATTERN);
+        assert_eq!(spec.mergeable_ranks, *bpe.mergeable_ranks());
+        assert_eq!(spec.special_tokens, bpe.special_tokens_encoder);
+    }
+
+    #[test]
+    fn test_dump_tokenizer_json_shared_special_rank() {
+        let spec = load(&setup_tokenizer(byte_level())).unwrap();
+        let special_tokens = HashMap::from_iter([
+            ("<|endofprompt|>".to_string(), 300),
+            ("<|reserved_300|>".to_string(), 300),
+            ("<|endoftext|>".to_string(), 301),
+        ]);
+        let bpe = CoreBPE::new_internal(spec.mergeable_ranks, special_tokens, BYTE_LEVEL_PATTERN)
+            .unwrap();
+        let err = bpe.to_tokenizer_json(Vec::new()).unwrap_err();
+        assert!(
+            err.message
+                .contains(r#"["<|endofprompt|>", "<|reserved_300|>"] share rank 300"#),
+            "{}",
+            err.message
+        );
+    }
+}
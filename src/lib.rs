@@ -1,5 +1,7 @@
+use std::borrow::Cow;
 use std::collections::HashSet;
 use std::num::NonZeroU64;
+use std::ops::ControlFlow;
 use std::thread;
 
 use fancy_regex::Regex;
@@ -7,8 +9,21 @@ use fancy_regex::Regex;
 use pyo3::prelude::*;
 use rustc_hash::FxHashMap as HashMap;
 
+use crate::vocab::{Ranks, Vocab};
+
+pub mod batch;
+pub mod chunking;
+pub mod encoding;
+pub mod huggingface;
+pub mod load;
+pub mod model;
+pub mod openai_public;
 #[cfg(feature = "python")]
 mod py;
+pub mod registry;
+pub mod snapshot;
+pub mod streaming;
+mod vocab;
 
 pub type Rank = u32;
 
@@ -44,8 +59,43 @@ struct State {
     cur_rank: Rank,
 }
 
-fn _byte_pair_merge_large(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<Rank> {
+/// Buffers for the merge functions, which counting reuses across pieces so that it does not
+/// allocate for each one.
+#[derive(Default)]
+pub(crate) struct MergeScratch {
+    parts: Vec<(usize, Rank)>,
+    state: Vec<State>,
+    heap: BinaryHeap<Merge>,
+}
+
+fn _byte_pair_merge_large(ranks: &impl Ranks, piece: &[u8]) -> Vec<Rank> {
     let mut state = Vec::with_capacity(piece.len());
+    let mut heap = BinaryHeap::with_capacity(piece.len());
+    _byte_pair_merge_large_into(ranks, piece, &mut state, &mut heap);
+
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < state.len() {
+        if state[i].cur_rank != Rank::MAX {
+            result.push(state[i].cur_rank);
+        } else {
+            result.push(ranks.rank(&piece[i..state[i].end]).unwrap());
+        }
+        i = state[i].end;
+    }
+    result
+}
+
+/// Merges `piece`, leaving the tokens in `state`: the first starts at 0 and ends at
+/// `state[0].end`, the next ends at `state[state[0].end].end`, and so on.
+fn _byte_pair_merge_large_into(
+    ranks: &impl Ranks,
+    piece: &[u8],
+    state: &mut Vec<State>,
+    heap: &mut BinaryHeap<Merge>,
+) {
+    state.clear();
+    state.reserve(piece.len());
     state.push(State {
         prev: usize::MAX,
         end: 1,
@@ -54,9 +104,10 @@ fn _byte_pair_merge_large(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<R
         cur_rank: Rank::MAX,
     });
 
-    let mut heap = BinaryHeap::with_capacity(piece.len());
+    heap.clear();
+    heap.reserve(piece.len());
     for i in 0..piece.len() - 1 {
-        if let Some(&rank) = ranks.get(&piece[i..i + 2]) {
+        if let Some(rank) = ranks.rank(&piece[i..i + 2]) {
             heap.push(Merge { start: i, rank });
             state[i].next_rank = rank;
         }
@@ -85,7 +136,7 @@ fn _byte_pair_merge_large(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<R
             state[start].next_end = next_end_item;
             state[start].next_rank = Rank::MAX; // Always invalidate the old merge
             if next_end_item <= piece.len()
-                && let Some(&rank) = ranks.get(&piece[start..next_end_item])
+                && let Some(rank) = ranks.rank(&piece[start..next_end_item])
             {
                 // We have a valid potential merge!
                 heap.push(Merge { start, rank });
@@ -111,43 +162,38 @@ fn _byte_pair_merge_large(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<R
         // Merge left and right into a single token
         state[left_start].cur_rank = state[left_start].next_rank;
         state[left_start].end = right_end;
-        potential_merge(&mut state, &mut heap, left_start, right_next_end);
+        potential_merge(state, heap, left_start, right_next_end);
         if right_end < state.len() {
             state[right_end].prev = left_start;
         }
         // Update the merge that ends at left_start
         if left_start > 0 {
             let prev_start = state[left_start].prev;
-            potential_merge(&mut state, &mut heap, prev_start, right_end);
+            potential_merge(state, heap, prev_start, right_end);
         }
         // Invalidate the merge starting at right_start, so we ignore it when it comes off the heap
         state[right_start].next_rank = Rank::MAX;
     }
+}
 
-    let mut result = Vec::new();
-    let mut i = 0;
-    while i < state.len() {
-        if state[i].cur_rank != Rank::MAX {
-            result.push(state[i].cur_rank);
-        } else {
-            result.push(ranks[&piece[i..state[i].end]]);
-        }
-        i = state[i].end;
-    }
-    result
+fn _byte_pair_merge(ranks: &impl Ranks, piece: &[u8]) -> Vec<(usize, Rank)> {
+    let mut parts = Vec::with_capacity(piece.len() + 1);
+    _byte_pair_merge_into(ranks, piece, &mut parts);
+    parts
 }
 
-fn _byte_pair_merge(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize, Rank)> {
+fn _byte_pair_merge_into(ranks: &impl Ranks, piece: &[u8], parts: &mut Vec<(usize, Rank)>) {
     // This is a vector of (start, rank).
     // The rank is of the pair starting at position start.
-    let mut parts = Vec::with_capacity(piece.len() + 1);
+    parts.clear();
+    parts.reserve(piece.len() + 1);
 
     // Note that we hash bytes when indexing into `ranks`, not token pairs. As long as we train BPE
     // the way we currently do, this is equivalent. An easy way to break this would be to decouple
     // merge priority from token index or to prevent specific token merges.
     let mut min_rank: (Rank, usize) = (Rank::MAX, usize::MAX);
     for i in 0..piece.len() - 1 {
-        let rank = *ranks.get(&piece[i..i + 2]).unwrap_or(&Rank::MAX);
+        let rank = ranks.rank(&piece[i..i + 2]).unwrap_or(Rank::MAX);
         if rank < min_rank.0 {
             min_rank = (rank, i);
         }
@@ -162,9 +208,9 @@ fn _byte_pair_merge(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize,
             if (i + 3) < parts.len() {
                 // Similar to `piece[i..i + 2]` above. The +3 is because we haven't yet deleted
                 // parts[i + 1], see comment in the main loop.
-                *ranks
-                    .get(&piece[parts[i].0..parts[i + 3].0])
-                    .unwrap_or(&Rank::MAX)
+                ranks
+                    .rank(&piece[parts[i].0..parts[i + 3].0])
+                    .unwrap_or(Rank::MAX)
             } else {
                 Rank::MAX
             }
@@ -180,9 +226,9 @@ fn _byte_pair_merge(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize,
         // Update parts[i] and parts[i - 1] before removing parts[i + 1], since
         // `parts.remove(i + 1)` will thrash the cache.
         if i > 0 {
-            parts[i - 1].1 = get_rank(&parts, i - 1);
+            parts[i - 1].1 = get_rank(parts, i - 1);
         }
-        parts[i].1 = get_rank(&parts, i);
+        parts[i].1 = get_rank(parts, i);
         parts.remove(i + 1);
 
         min_rank = (Rank::MAX, usize::MAX);
@@ -192,19 +238,39 @@ fn _byte_pair_merge(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize,
             }
         }
     }
-    parts
+}
+
+/// The number of tokens `_byte_pair_encode` would return, using `scratch` instead of allocating.
+fn _byte_pair_count(ranks: &impl Ranks, piece: &[u8], scratch: &mut MergeScratch) -> usize {
+    if piece.len() < 100 {
+        _byte_pair_merge_into(ranks, piece, &mut scratch.parts);
+        // There is one more part boundary than there are tokens
+        return scratch.parts.len() - 1;
+    }
+    _byte_pair_merge_large_into(ranks, piece, &mut scratch.state, &mut scratch.heap);
+    let mut count = 0;
+    let mut i = 0;
+    while i < scratch.state.len() {
+        count += 1;
+        i = scratch.state[i].end;
+    }
+    count
 }
 
 pub fn byte_pair_encode(piece: &[u8], ranks: &HashMap<Vec<u8>, Rank>) -> Vec<Rank> {
+    _byte_pair_encode(piece, ranks)
+}
+
+fn _byte_pair_encode(piece: &[u8], ranks: &impl Ranks) -> Vec<Rank> {
     let piece_len = piece.len();
 
     if piece_len == 1 {
-        return vec![ranks[piece]];
+        return vec![ranks.rank(piece).unwrap()];
     }
     if piece_len < 100 {
         return _byte_pair_merge(ranks, piece)
             .windows(2)
-            .map(|part| ranks[&piece[part[0].0..part[1].0]])
+            .map(|part| ranks.rank(&piece[part[0].0..part[1].0]).unwrap())
             .collect();
     }
     _byte_pair_merge_large(ranks, piece)
@@ -241,6 +307,8 @@ pub fn byte_pair_split<'a>(piece: &'a [u8], ranks: &HashMap<Vec<u8>, Rank>) -> V
 // =========
 // I tried using `rayon`. It wasn't really faster than using Python threads and releasing the GIL.
 // So goodbye `rayon`! Let thread count etc be in control of our Python users.
+// The batch methods in `batch.rs` use plain scoped threads, with a thread count chosen by the
+// caller, and are what Python's `encode_batch` etc call.
 //
 // Caching
 // =======
@@ -300,6 +368,205 @@ impl std::fmt::Display for DecodeError {
 
 impl std::error::Error for DecodeError {}
 
+impl From<DecodeKeyError> for DecodeError {
+    fn from(e: DecodeKeyError) -> Self {
+        DecodeError {
+            message: e.to_string(),
+        }
+    }
+}
+
+/// How [`CoreBPE::decode`] handles bytes that are not valid UTF-8, since tokens need not end on
+/// character boundaries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DecodeMode {
+    /// Return an error, like `errors="strict"` in Python.
+    Strict,
+    /// Drop invalid bytes, like `errors="ignore"` in Python.
+    Lossy,
+    /// Replace invalid sequences with U+FFFD, like `errors="replace"` in Python.
+    #[default]
+    Replace,
+}
+
+/// The unit that token offsets are reported in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OffsetUnit {
+    /// Bytes of the UTF-8 encoding.
+    #[default]
+    Bytes,
+    /// Unicode scalar values, i.e. Rust `char`s or Python `str` indices.
+    Chars,
+    /// UTF-16 code units, i.e. JavaScript string indices.
+    Utf16,
+}
+
+impl OffsetUnit {
+    fn width(self, c: char) -> usize {
+        match self {
+            OffsetUnit::Bytes => c.len_utf8(),
+            OffsetUnit::Chars => 1,
+            OffsetUnit::Utf16 => c.len_utf16(),
+        }
+    }
+}
+
+/// Converts nondecreasing byte offsets into `text` to another unit in a single pass.
+struct OffsetCursor<'a> {
+    text: &'a str,
+    unit: OffsetUnit,
+    byte: usize,
+    offset: usize,
+}
+
+impl OffsetCursor<'_> {
+    /// Moves to the start of the character containing byte `b`.
+    fn advance(&mut self, b: usize) -> Option<char> {
+        for c in self.text[self.byte..].chars() {
+            if self.byte + c.len_utf8() > b {
+                return Some(c);
+            }
+            self.byte += c.len_utf8();
+            self.offset += self.unit.width(c);
+        }
+        None
+    }
+
+    /// The offset of the character containing byte `b`.
+    fn floor(&mut self, b: usize) -> usize {
+        self.advance(b);
+        self.offset
+    }
+
+    /// The offset just past the character containing byte `b - 1`.
+    fn ceil(&mut self, b: usize) -> usize {
+        match self.advance(b) {
+            Some(c) if self.byte < b => self.offset + self.unit.width(c),
+            _ => self.offset,
+        }
+    }
+}
+
+/// Which special tokens are encoded as special tokens, like `allowed_special` in Python. Special
+/// tokens that are not allowed are encoded as ordinary text.
+///
+/// The encode methods take anything that converts into this, including `&HashSet<&str>`, which
+/// becomes [`AllowedSpecial::Only`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AllowedSpecial<'a> {
+    /// Every special token, like `allowed_special="all"`.
+    All,
+    /// No special tokens, like `allowed_special=set()`.
+    #[default]
+    None_,
+    /// These special tokens, like `allowed_special={...}`.
+    Only(&'a HashSet<&'a str>),
+    /// Every special token except these.
+    AllExcept(&'a HashSet<&'a str>),
+}
+
+impl AllowedSpecial<'_> {
+    pub fn allows(&self, token: &str) -> bool {
+        match self {
+            AllowedSpecial::All => true,
+            AllowedSpecial::None_ => false,
+            AllowedSpecial::Only(tokens) => tokens.contains(token),
+            AllowedSpecial::AllExcept(tokens) => !tokens.contains(token),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            AllowedSpecial::None_ => true,
+            AllowedSpecial::Only(tokens) => tokens.is_empty(),
+            AllowedSpecial::All | AllowedSpecial::AllExcept(_) => false,
+        }
+    }
+}
+
+impl<'a, 'b: 'a, 'c: 'a> From<&'b HashSet<&'c str>> for AllowedSpecial<'a> {
+    fn from(tokens: &'b HashSet<&'c str>) -> Self {
+        AllowedSpecial::Only(tokens)
+    }
+}
+
+/// Which special tokens make [`CoreBPE::encode_checked`] fail if they occur in the text, like
+/// `disallowed_special` in Python.
+///
+/// Special tokens that are neither allowed nor disallowed are encoded as ordinary text. So to
+/// encode a particular special token as text while still guarding against the others, use
+/// [`DisallowedSpecial::AllExcept`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DisallowedSpecial<'a> {
+    /// Every special token that is not allowed, like `disallowed_special="all"`.
+    #[default]
+    All,
+    /// No special tokens, like `disallowed_special=()`.
+    None_,
+    /// These strings, even if they are allowed special tokens or not special tokens at all, as in
+    /// Python.
+    Only(&'a HashSet<&'a str>),
+    /// Every special token that is not allowed, except these.
+    AllExcept(&'a HashSet<&'a str>),
+}
+
+impl DisallowedSpecial<'_> {
+    pub fn disallows(&self, token: &str, allowed_special: AllowedSpecial) -> bool {
+        match self {
+            DisallowedSpecial::All => !allowed_special.allows(token),
+            DisallowedSpecial::None_ => false,
+            DisallowedSpecial::Only(tokens) => tokens.contains(token),
+            DisallowedSpecial::AllExcept(tokens) => {
+                !allowed_special.allows(token) && !tokens.contains(token)
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            DisallowedSpecial::None_ => true,
+            DisallowedSpecial::Only(tokens) => tokens.is_empty(),
+            DisallowedSpecial::All | DisallowedSpecial::AllExcept(_) => false,
+        }
+    }
+}
+
+impl<'a, 'b: 'a, 'c: 'a> From<&'b HashSet<&'c str>> for DisallowedSpecial<'a> {
+    fn from(tokens: &'b HashSet<&'c str>) -> Self {
+        DisallowedSpecial::Only(tokens)
+    }
+}
+
+/// Which part of the text [`CoreBPE::encode_truncated`] keeps when it has to cut.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Truncation<'a> {
+    /// Keep the start of the text.
+    Head,
+    /// Keep the end of the text.
+    Tail,
+    /// Keep both ends, joined by `ellipsis`. The ellipsis is encoded as ordinary text and counts
+    /// towards the budget.
+    Middle { ellipsis: &'a str },
+}
+
+/// The result of [`CoreBPE::encode_truncated`].
+///
+/// The kept text is `text[..head_end]`, then the ellipsis if the text was cut in the middle, then
+/// `text[tail_start..]`. Both offsets are byte offsets on character and token boundaries. If
+/// nothing was cut, `head_end == tail_start == text.len()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Truncated {
+    pub tokens: Vec<Rank>,
+    pub head_end: usize,
+    pub tail_start: usize,
+}
+
+impl Truncated {
+    pub fn is_truncated(&self) -> bool {
+        self.head_end < self.tail_start
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct EncodeError {
     pub message: String,
@@ -313,18 +580,55 @@ impl std::fmt::Display for EncodeError {
 
 impl std::error::Error for EncodeError {}
 
+/// An error from [`CoreBPE::encode_checked`].
+#[derive(Debug, Clone)]
+pub enum CheckedEncodeError {
+    /// The text contains `token`, a disallowed special token, starting at byte `offset`.
+    DisallowedSpecial {
+        token: String,
+        offset: usize,
+    },
+    Encode(EncodeError),
+}
+
+impl std::fmt::Display for CheckedEncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CheckedEncodeError::DisallowedSpecial { token, offset } => write!(
+                f,
+                "Encountered text corresponding to disallowed special token {token:?} at byte {offset}"
+            ),
+            CheckedEncodeError::Encode(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for CheckedEncodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CheckedEncodeError::Encode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<EncodeError> for CheckedEncodeError {
+    fn from(e: EncodeError) -> Self {
+        CheckedEncodeError::Encode(e)
+    }
+}
+
 const MAX_NUM_THREADS: usize = 128;
 
 #[cfg_attr(feature = "python", pyclass(frozen))]
 #[derive(Clone)]
 pub struct CoreBPE {
-    encoder: HashMap<Vec<u8>, Rank>,
+    /// The mergeable tokens, in place of the `encoder`, `decoder` and `sorted_token_bytes` maps.
+    vocab: Vocab,
     special_tokens_encoder: HashMap<String, Rank>,
-    decoder: HashMap<Rank, Vec<u8>>,
     special_tokens_decoder: HashMap<Rank, Vec<u8>>,
     regex_tls: Vec<Regex>,
     special_regex_tls: Vec<Regex>,
-    sorted_token_bytes: Vec<Vec<u8>>,
 }
 
 impl CoreBPE {
@@ -342,21 +646,46 @@ impl CoreBPE {
     /// Decodes tokens into a list of bytes.
     ///
     /// The bytes are not gauranteed to be a valid utf-8 string.
-    fn decode_bytes(&self, tokens: &[Rank]) -> Result<Vec<u8>, DecodeKeyError> {
+    pub fn decode_bytes(&self, tokens: &[Rank]) -> Result<Vec<u8>, DecodeKeyError> {
         let mut ret = Vec::with_capacity(tokens.len() * 2);
         for &token in tokens {
-            let token_bytes = match self.decoder.get(&token) {
-                Some(bytes) => bytes,
-                None => self
-                    .special_tokens_decoder
-                    .get(&token)
-                    .ok_or(DecodeKeyError { token })?,
-            };
-            ret.extend(token_bytes);
+            ret.extend(self.decode_single_token_bytes(token)?);
         }
         Ok(ret)
     }
 
+    /// Decodes tokens into a string, handling invalid UTF-8 according to `mode`.
+    pub fn decode(&self, tokens: &[Rank], mode: DecodeMode) -> Result<String, DecodeError> {
+        let bytes = self.decode_bytes(tokens)?;
+        match mode {
+            DecodeMode::Strict => String::from_utf8(bytes).map_err(|e| DecodeError {
+                message: e.to_string(),
+            }),
+            DecodeMode::Lossy => Ok(bytes.utf8_chunks().map(|chunk| chunk.valid()).collect()),
+            DecodeMode::Replace => Ok(String::from_utf8_lossy(&bytes).into_owned()),
+        }
+    }
+
+    /// Decodes a token into bytes. This decodes special tokens too.
+    pub fn decode_single_token_bytes(&self, token: Rank) -> Result<&[u8], DecodeKeyError> {
+        match self.vocab.token(token) {
+            Some(bytes) => Ok(bytes),
+            None => self
+                .special_tokens_decoder
+                .get(&token)
+                .map(Vec::as_slice)
+                .ok_or(DecodeKeyError { token }),
+        }
+    }
+
+    /// Decodes each token into its bytes. Useful for visualising tokenisation.
+    pub fn decode_tokens_bytes(&self, tokens: &[Rank]) -> Result<Vec<&[u8]>, DecodeKeyError> {
+        tokens
+            .iter()
+            .map(|&token| self.decode_single_token_bytes(token))
+            .collect()
+    }
+
     pub fn encode_ordinary(&self, text: &str) -> Vec<Rank> {
         // This is the core of the encoding logic; the other functions in here
         // just make things complicated :-)
@@ -364,41 +693,135 @@ impl CoreBPE {
         let mut ret = vec![];
         for mat in regex.find_iter(text) {
             let piece = mat.unwrap().as_str().as_bytes();
-            match self.encoder.get(piece) {
-                Some(token) => ret.push(*token),
-                None => ret.extend(&byte_pair_encode(piece, &self.encoder)),
+            match self.vocab.get(piece) {
+                Some(token) => ret.push(token),
+                None => ret.extend(&self.vocab.byte_pair_encode(piece)),
             }
         }
         ret
     }
 
-    pub fn encode(
+    /// Encodes bytes that need not be valid UTF-8, with special tokens encoded as ordinary text.
+    ///
+    /// Valid UTF-8 is split with the regex as in `encode_ordinary`. Each run of invalid bytes is
+    /// joined to the piece before it, along with any whitespace pieces that could merge with
+    /// that, and the lot is encoded with BPE as one piece. That is likely what a model would see,
+    /// e.g. if the invalid bytes are a truncated character. Decoding the tokens always gives back
+    /// `bytes`.
+    pub fn encode_bytes(&self, bytes: &[u8]) -> Vec<Rank> {
+        let mut tokens = vec![];
+        // Bytes of the piece before an invalid run, and the run itself
+        let mut unstable_bytes = vec![];
+        for chunk in bytes.utf8_chunks() {
+            if !chunk.valid().is_empty() {
+                if !unstable_bytes.is_empty() {
+                    self.encode_piece(&std::mem::take(&mut unstable_bytes), &mut tokens);
+                }
+                let (chunk_tokens, last_piece_token_len) =
+                    self.encode(chunk.valid(), AllowedSpecial::None_).unwrap();
+                if chunk.invalid().is_empty() {
+                    // Only the last chunk can be valid to the end
+                    tokens.extend(chunk_tokens);
+                    continue;
+                }
+                // We need to do the regex splitting in Unicode space, so we make our best guess
+                // at where we would have splits: lop off the tokens from the last piece and run
+                // BPE on its bytes together with the invalid ones.
+                // Niche, but note this may not be correct if we'd have had a regex split between
+                // the valid UTF-8 and the invalid bytes.
+                let (mut chunk_tokens, last_piece_token_len) =
+                    self._increase_last_piece_token_len(chunk_tokens, last_piece_token_len);
+                let stable_len = chunk_tokens.len() - last_piece_token_len;
+                unstable_bytes = self.decode_bytes(&chunk_tokens[stable_len..]).unwrap();
+                chunk_tokens.truncate(stable_len);
+                tokens.extend(chunk_tokens);
+            }
+            unstable_bytes.extend_from_slice(chunk.invalid());
+        }
+        if !unstable_bytes.is_empty() {
+            self.encode_piece(&unstable_bytes, &mut tokens);
+        }
+        tokens
+    }
+
+    /// Encodes bytes with BPE alone, without splitting them with the regex first.
+    ///
+    /// This is meant for binary-ish data, like base64 blobs, where the regex split gets in the
+    /// way. BPE runs over consecutive windows of `max_window` bytes, so memory use is bounded by
+    /// the window size rather than by the length of `bytes`, and tokens never span two windows.
+    /// Special tokens are not recognised.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_window` is 0.
+    pub fn encode_raw(&self, bytes: &[u8], max_window: usize) -> Vec<Rank> {
+        let mut tokens = vec![];
+        for window in bytes.chunks(max_window) {
+            tokens.extend(self.vocab.byte_pair_merge_large(window));
+        }
+        tokens
+    }
+
+    /// Encodes a regex piece onto `ret`, returning the number of tokens.
+    fn encode_piece(&self, piece: &[u8], ret: &mut Vec<Rank>) -> usize {
+        match self.vocab.get(piece) {
+            Some(token) => {
+                ret.push(token);
+                1
+            }
+            None => {
+                let tokens = self.vocab.byte_pair_encode(piece);
+                ret.extend(&tokens);
+                tokens.len()
+            }
+        }
+    }
+
+    /// The number of tokens `encode_piece` would produce, without collecting them. The merge
+    /// buffers in `scratch` are reused, so counting many pieces does not allocate for each one.
+    fn count_piece(&self, piece: &[u8], scratch: &mut MergeScratch) -> usize {
+        if self.vocab.contains(piece) {
+            return 1;
+        }
+        self.vocab.byte_pair_count(piece, scratch)
+    }
+
+    /// Finds the first special token in `text` at or after `start` that `allowed_special` allows.
+    fn find_allowed_special<'t>(
         &self,
-        text: &str,
-        allowed_special: &HashSet<&str>,
-    ) -> Result<(Vec<Rank>, usize), EncodeError> {
+        text: &'t str,
+        start: usize,
+        allowed_special: AllowedSpecial,
+    ) -> Option<fancy_regex::Match<'t>> {
+        // Don't scan the text when nothing could match
+        if allowed_special.is_empty() {
+            return None;
+        }
         let special_regex = self._get_tl_special_regex();
+        let mut start_find = start;
+        while let Some(m) = special_regex.find_from_pos(text, start_find).unwrap() {
+            if allowed_special.allows(m.as_str()) {
+                return Some(m);
+            }
+            start_find = m.start() + 1;
+        }
+        None
+    }
+
+    /// Walks `text` the way `encode` does, calling `f(piece, start, special)` for each regex
+    /// piece or allowed special token, where `start` is the byte offset of `piece` in `text` and
+    /// `special` is the special token, if it is one. `f` can stop the walk early.
+    fn for_each_piece(
+        &self,
+        text: &str,
+        allowed_special: AllowedSpecial,
+        mut f: impl FnMut(&[u8], usize, Option<Rank>) -> ControlFlow<()>,
+    ) -> Result<(), EncodeError> {
         let regex = self._get_tl_regex();
-        let mut ret = vec![];
 
         let mut start = 0;
-        let mut last_piece_token_len = 0;
         loop {
-            let mut next_special;
-            let mut start_find = start;
-            loop {
-                // Find the next allowed special token, if any
-                next_special = special_regex.find_from_pos(text, start_find).unwrap();
-                match next_special {
-                    Some(m) => {
-                        if allowed_special.contains(&text[m.start()..m.end()]) {
-                            break;
-                        }
-                        start_find = m.start() + 1;
-                    }
-                    None => break,
-                }
-            }
+            let next_special = self.find_allowed_special(text, start, allowed_special);
             let end = next_special.map_or(text.len(), |m| m.start());
 
             // Okay, here we go, compare this logic to encode_ordinary
@@ -413,14 +836,9 @@ impl CoreBPE {
                 };
 
                 let piece = mat.as_str().as_bytes();
-                if let Some(token) = self.encoder.get(piece) {
-                    last_piece_token_len = 1;
-                    ret.push(*token);
-                    continue;
+                if f(piece, start + mat.start(), None).is_break() {
+                    return Ok(());
                 }
-                let tokens = byte_pair_encode(piece, &self.encoder);
-                last_piece_token_len = tokens.len();
-                ret.extend(&tokens);
             }
 
             match next_special {
@@ -428,19 +846,363 @@ impl CoreBPE {
                 Some(m) => {
                     let piece = m.as_str();
                     let token = self.special_tokens_encoder[piece];
-                    ret.push(token);
+                    if f(piece.as_bytes(), m.start(), Some(token)).is_break() {
+                        return Ok(());
+                    }
                     start = m.end();
-                    last_piece_token_len = 0;
                 }
                 None => break,
             }
         }
+        Ok(())
+    }
+
+    pub fn encode<'a>(
+        &self,
+        text: &str,
+        allowed_special: impl Into<AllowedSpecial<'a>>,
+    ) -> Result<(Vec<Rank>, usize), EncodeError> {
+        let allowed_special = allowed_special.into();
+        let mut ret = vec![];
+        let mut last_piece_token_len = 0;
+        self.for_each_piece(text, allowed_special, |piece, _, special| {
+            last_piece_token_len = match special {
+                Some(token) => {
+                    ret.push(token);
+                    0
+                }
+                None => self.encode_piece(piece, &mut ret),
+            };
+            ControlFlow::Continue(())
+        })?;
 
         // last_piece_token_len is how many tokens came from the last regex split. This is used
         // for determining unstable tokens, since you can't merge across (stable) regex splits
         Ok((ret, last_piece_token_len))
     }
 
+    /// Like `encode`, but first checks that `text` contains no special tokens that
+    /// `disallowed_special` disallows, like `encode` in Python does.
+    ///
+    /// Special tokens that are neither allowed nor disallowed are encoded as ordinary text.
+    /// As in Python, strings in [`DisallowedSpecial::Only`] that are not special tokens are
+    /// disallowed too. If several disallowed strings occur, the error is for the first one.
+    pub fn encode_checked<'a>(
+        &self,
+        text: &str,
+        allowed_special: impl Into<AllowedSpecial<'a>>,
+        disallowed_special: impl Into<DisallowedSpecial<'a>>,
+    ) -> Result<Vec<Rank>, CheckedEncodeError> {
+        let allowed_special = allowed_special.into();
+        let disallowed_special = disallowed_special.into();
+        self.check_disallowed_special(text, allowed_special, disallowed_special)?;
+        let (tokens, _) = self.encode(text, allowed_special)?;
+        Ok(tokens)
+    }
+
+    fn check_disallowed_special(
+        &self,
+        text: &str,
+        allowed_special: AllowedSpecial,
+        disallowed_special: DisallowedSpecial,
+    ) -> Result<(), CheckedEncodeError> {
+        if disallowed_special.is_empty() {
+            return Ok(());
+        }
+        // The special token regex cannot find disallowed strings that are not special tokens, so
+        // look for those separately
+        let mut first_other: Option<(usize, &str)> = None;
+        if let DisallowedSpecial::Only(tokens) = disallowed_special {
+            for &token in tokens {
+                if self.special_tokens_encoder.contains_key(token) {
+                    continue;
+                }
+                if let Some(offset) = text.find(token)
+                    && first_other.is_none_or(|(first, _)| offset < first)
+                {
+                    first_other = Some((offset, token));
+                }
+            }
+        }
+        let special_regex = self._get_tl_special_regex();
+        let mut start_find = 0;
+        // Like in `for_each_piece`, a match can hide another special token starting inside it,
+        // so look again one byte on rather than after the match
+        while let Some(m) = special_regex.find_from_pos(text, start_find).unwrap() {
+            if first_other.is_some_and(|(first, _)| first < m.start()) {
+                break;
+            }
+            if disallowed_special.disallows(m.as_str(), allowed_special) {
+                return Err(CheckedEncodeError::DisallowedSpecial {
+                    token: m.as_str().to_string(),
+                    offset: m.start(),
+                });
+            }
+            start_find = m.start() + 1;
+        }
+        match first_other {
+            Some((offset, token)) => Err(CheckedEncodeError::DisallowedSpecial {
+                token: token.to_string(),
+                offset,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Like `encode`, but returns each token with the byte range of `text` it was encoded from.
+    ///
+    /// Tokens cover `text` exactly, in order, so the ranges are contiguous. A range need not
+    /// start or end on a character boundary, since BPE can split a multi-byte character.
+    pub fn encode_with_offsets<'a>(
+        &self,
+        text: &str,
+        allowed_special: impl Into<AllowedSpecial<'a>>,
+    ) -> Result<Vec<(Rank, usize, usize)>, EncodeError> {
+        let allowed_special = allowed_special.into();
+        self.encode_with_offsets_up_to(text, allowed_special, usize::MAX)
+    }
+
+    /// Like `encode_with_offsets`, but stops at the end of the piece where the number of tokens
+    /// passes `limit`.
+    fn encode_with_offsets_up_to(
+        &self,
+        text: &str,
+        allowed_special: AllowedSpecial,
+        limit: usize,
+    ) -> Result<Vec<(Rank, usize, usize)>, EncodeError> {
+        let mut ret = vec![];
+        let mut tokens = vec![];
+        self.for_each_piece(text, allowed_special, |piece, start, special| {
+            if let Some(token) = special {
+                ret.push((token, start, start + piece.len()));
+            } else {
+                tokens.clear();
+                self.encode_piece(piece, &mut tokens);
+                let mut token_start = start;
+                for &token in &tokens {
+                    let token_end = token_start + self.vocab[token].len();
+                    ret.push((token, token_start, token_end));
+                    token_start = token_end;
+                }
+            }
+            if ret.len() > limit {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })?;
+        Ok(ret)
+    }
+
+    /// The number of tokens `encode` would return, without collecting them.
+    pub fn count_tokens<'a>(
+        &self,
+        text: &str,
+        allowed_special: impl Into<AllowedSpecial<'a>>,
+    ) -> Result<usize, EncodeError> {
+        let allowed_special = allowed_special.into();
+        let mut scratch = MergeScratch::default();
+        let mut count = 0;
+        self.for_each_piece(text, allowed_special, |piece, _, special| {
+            count += match special {
+                Some(_) => 1,
+                None => self.count_piece(piece, &mut scratch),
+            };
+            ControlFlow::Continue(())
+        })?;
+        Ok(count)
+    }
+
+    /// The number of tokens `encode_ordinary` would return, if it is at most `limit`.
+    ///
+    /// Returns `None` as soon as the count passes `limit`, without looking at the rest of the
+    /// text. This is the cheap way to check whether text fits in a context window.
+    pub fn count_tokens_up_to(&self, text: &str, limit: usize) -> Option<usize> {
+        let regex = self._get_tl_regex();
+        let mut scratch = MergeScratch::default();
+        let mut count = 0;
+        for mat in regex.find_iter(text) {
+            count += self.count_piece(mat.unwrap().as_str().as_bytes(), &mut scratch);
+            if count > limit {
+                return None;
+            }
+        }
+        Some(count)
+    }
+
+    /// Like `encode_with_offsets`, but with offsets in the given unit.
+    ///
+    /// When a token boundary falls inside a multi-byte character, the token's span is widened to
+    /// whole characters: the start rounds down to the start of the character, and the end rounds
+    /// up to its end. So every token's span includes each character it has bytes of, and tokens
+    /// that split a character get overlapping spans. For bytes, offsets are returned as is.
+    pub fn encode_with_offsets_in<'a>(
+        &self,
+        text: &str,
+        allowed_special: impl Into<AllowedSpecial<'a>>,
+        unit: OffsetUnit,
+    ) -> Result<Vec<(Rank, usize, usize)>, EncodeError> {
+        let allowed_special = allowed_special.into();
+        let mut ret = self.encode_with_offsets(text, allowed_special)?;
+        if unit == OffsetUnit::Bytes {
+            return Ok(ret);
+        }
+        let mut cursor = OffsetCursor {
+            text,
+            unit,
+            byte: 0,
+            offset: 0,
+        };
+        for (_, start, end) in &mut ret {
+            *start = cursor.floor(*start);
+            *end = cursor.ceil(*end);
+        }
+        Ok(ret)
+    }
+
+    /// Encodes `text` into at most `max_tokens` tokens, cutting it as `truncation` says if it
+    /// does not fit.
+    ///
+    /// Cuts are made on token and character boundaries, and each kept part of the text is encoded
+    /// on its own with `encode`, so the tokens decode to exactly the kept text. With
+    /// [`Truncation::Middle`], the parts are the head, the ellipsis and the tail, and the tail
+    /// always starts after the head ends. Slicing the tokens of the full text instead can split a
+    /// character, and since BPE merges depend on what follows, the kept text can encode to more tokens than were
+    /// kept. Cuts are moved back until the kept text fits, so fewer than `max_tokens` tokens can
+    /// be returned.
+    pub fn encode_truncated<'a>(
+        &self,
+        text: &str,
+        allowed_special: impl Into<AllowedSpecial<'a>>,
+        max_tokens: usize,
+        truncation: Truncation,
+    ) -> Result<Truncated, EncodeError> {
+        let allowed_special = allowed_special.into();
+        let offsets = match truncation {
+            Truncation::Head => {
+                self.encode_with_offsets_up_to(text, allowed_special, max_tokens)?
+            }
+            Truncation::Tail | Truncation::Middle { .. } => {
+                self.encode_with_offsets(text, allowed_special)?
+            }
+        };
+        if offsets.len() <= max_tokens {
+            return Ok(Truncated {
+                tokens: offsets.into_iter().map(|(token, _, _)| token).collect(),
+                head_end: text.len(),
+                tail_start: text.len(),
+            });
+        }
+
+        match truncation {
+            Truncation::Head => {
+                let (tokens, head_end) =
+                    self.truncate_head(text, allowed_special, &offsets, max_tokens)?;
+                Ok(Truncated {
+                    tokens,
+                    head_end,
+                    tail_start: text.len(),
+                })
+            }
+            Truncation::Tail => {
+                let (tokens, tail_start) =
+                    self.truncate_tail(text, allowed_special, &offsets, max_tokens, 0)?;
+                Ok(Truncated {
+                    tokens,
+                    head_end: 0,
+                    tail_start,
+                })
+            }
+            Truncation::Middle { ellipsis } => {
+                let ellipsis_tokens = self.encode_ordinary(ellipsis);
+                let Some(budget) = max_tokens.checked_sub(ellipsis_tokens.len()) else {
+                    return Err(EncodeError {
+                        message: format!(
+                            "Ellipsis {ellipsis:?} is {} tokens, more than the budget of {max_tokens}",
+                            ellipsis_tokens.len()
+                        ),
+                    });
+                };
+                let (mut tokens, head_end) =
+                    self.truncate_head(text, allowed_special, &offsets, budget.div_ceil(2))?;
+                let (tail_tokens, tail_start) = self.truncate_tail(
+                    text,
+                    allowed_special,
+                    &offsets,
+                    budget - tokens.len(),
+                    // Encoded on their own, the head and tail can take fewer tokens than in the
+                    // full text and so meet, but the text does not fit, so something is cut
+                    head_end + 1,
+                )?;
+                tokens.extend(ellipsis_tokens);
+                tokens.extend(tail_tokens);
+                Ok(Truncated {
+                    tokens,
+                    head_end,
+                    tail_start,
+                })
+            }
+        }
+    }
+
+    /// Keeps the longest prefix of `text` that ends at one of `offsets` and encodes to at most
+    /// `max_tokens` tokens. `offsets` must have more than `max_tokens` entries.
+    fn truncate_head(
+        &self,
+        text: &str,
+        allowed_special: AllowedSpecial,
+        offsets: &[(Rank, usize, usize)],
+        max_tokens: usize,
+    ) -> Result<(Vec<Rank>, usize), EncodeError> {
+        // `kept` is the number of tokens of the full text before the cut
+        let mut kept = max_tokens;
+        loop {
+            let cut = match kept {
+                0 => 0,
+                _ => offsets[kept - 1].2,
+            };
+            if !text.is_char_boundary(cut) {
+                kept -= 1;
+                continue;
+            }
+            let (tokens, _) = self.encode(&text[..cut], allowed_special)?;
+            if tokens.len() <= max_tokens {
+                return Ok((tokens, cut));
+            }
+            kept = kept.saturating_sub(tokens.len() - max_tokens);
+        }
+    }
+
+    /// Keeps the longest suffix of `text` that starts at one of `offsets`, no earlier than
+    /// `min_start`, and encodes to at most `max_tokens` tokens. `offsets` must cover all of `text`
+    /// and have more than `max_tokens` entries.
+    fn truncate_tail(
+        &self,
+        text: &str,
+        allowed_special: AllowedSpecial,
+        offsets: &[(Rank, usize, usize)],
+        max_tokens: usize,
+        min_start: usize,
+    ) -> Result<(Vec<Rank>, usize), EncodeError> {
+        // `dropped` is the number of tokens of the full text before the cut
+        let mut dropped = offsets.len() - max_tokens;
+        loop {
+            let cut = match offsets.get(dropped) {
+                Some(&(_, start, _)) => start,
+                None => text.len(),
+            };
+            if cut < min_start || !text.is_char_boundary(cut) {
+                dropped += 1;
+                continue;
+            }
+            let (tokens, _) = self.encode(&text[cut..], allowed_special)?;
+            if tokens.len() <= max_tokens {
+                return Ok((tokens, cut));
+            }
+            dropped += tokens.len() - max_tokens;
+        }
+    }
+
     fn _increase_last_piece_token_len(
         &self,
         tokens: Vec<Rank>,
@@ -454,9 +1216,9 @@ impl CoreBPE {
         // pattern. This can e.g. cause "\n" + " " to become "\n \n".
         // Here is a quick and dirty fix:
         {
-            let token_is_all_space = |token| {
-                self.decoder
-                    .get(token)
+            let token_is_all_space = |token: &Rank| {
+                self.vocab
+                    .token(*token)
                     .map(|token_bytes| {
                         token_bytes
                             .iter()
@@ -480,11 +1242,12 @@ impl CoreBPE {
         (tokens, last_piece_token_len)
     }
 
-    pub fn _encode_unstable_native(
+    pub fn _encode_unstable_native<'a>(
         &self,
         text: &str,
-        allowed_special: &HashSet<&str>,
+        allowed_special: impl Into<AllowedSpecial<'a>>,
     ) -> (Vec<Rank>, HashSet<Vec<Rank>>) {
+        let allowed_special = allowed_special.into();
         let (tokens, last_piece_token_len) = self.encode(text, allowed_special).unwrap();
         if last_piece_token_len == 0 {
             // If last_piece_token_len is zero, the last token was a special token and we have
@@ -511,16 +1274,8 @@ impl CoreBPE {
         // This is the easy bit. Just find all single tokens that start with unstable_bytes
         // (including tokens that exactly match unstable_bytes)
         // Separating this from the loop below helps with performance in a common case.
-        let mut point = self
-            .sorted_token_bytes
-            .partition_point(|x| x.as_slice() < unstable_bytes.as_slice());
-        while point < self.sorted_token_bytes.len()
-            && self.sorted_token_bytes[point].starts_with(&unstable_bytes)
-        {
-            completions.insert(vec![
-                self.encoder[self.sorted_token_bytes[point].as_slice()],
-            ]);
-            point += 1;
+        for token_bytes in self.vocab.with_prefix(&unstable_bytes) {
+            completions.insert(vec![self.vocab.get(token_bytes).unwrap()]);
         }
 
         // Now apply even more brute force. At every (other) possible position for the straddling
@@ -529,16 +1284,11 @@ impl CoreBPE {
         for i in 1..unstable_bytes.len() {
             let prefix = &unstable_bytes[..i];
             let suffix = &unstable_bytes[i..];
-            let mut point = self
-                .sorted_token_bytes
-                .partition_point(|x| x.as_slice() < suffix);
             // TODO: Perf optimisation if suffix starts with " "?
-            while point < self.sorted_token_bytes.len()
-                && self.sorted_token_bytes[point].starts_with(suffix)
-            {
-                let possibility = [prefix, self.sorted_token_bytes[point].as_slice()].concat();
+            for token_bytes in self.vocab.with_prefix(suffix) {
+                let possibility = [prefix, token_bytes].concat();
                 let encoded = match std::str::from_utf8(&possibility) {
-                    // Morally, this is byte_pair_encode(&possibility, &self.encoder)
+                    // Morally, this is self.vocab.byte_pair_encode(&possibility)
                     // But we might have introduced a regex split which would prevent merges.
                     // (particularly possible in the presence of unstable regex splits)
                     // So convert to UTF-8 and do regex splitting.
@@ -550,7 +1300,7 @@ impl CoreBPE {
                     // would be a regex split before the UTF-8 truncation point.
                     // Probably niche enough that no one will ever notice (after all, people didn't
                     // notice all the big holes in the previous unstable token implementation)
-                    Err(_) => byte_pair_encode(&possibility, &self.encoder),
+                    Err(_) => self.vocab.byte_pair_encode(&possibility),
                     // Something like the following is intriguing but incorrect:
                     // Err(e) => self.encode_ordinary(unsafe {
                     //     std::str::from_utf8_unchecked(&possibility[..e.valid_up_to()])
@@ -560,13 +1310,12 @@ impl CoreBPE {
                 let mut seq_len = 0;
                 for token in encoded {
                     seq.push(token);
-                    seq_len += self.decoder[&token].len();
+                    seq_len += self.vocab[token].len();
                     if seq_len >= unstable_bytes.len() {
                         break;
                     }
                 }
                 completions.insert(seq);
-                point += 1;
             }
         }
 
@@ -583,14 +1332,13 @@ impl CoreBPE {
             if unstable_bytes.len() - last_decoded.1 > 0
                 && last_decoded.0.is_some_and(|c| c.is_whitespace())
             {
-                let mut reencoded = byte_pair_encode(
-                    &unstable_bytes[..unstable_bytes.len() - last_decoded.1],
-                    &self.encoder,
-                );
-                reencoded.extend(byte_pair_encode(
-                    &unstable_bytes[unstable_bytes.len() - last_decoded.1..],
-                    &self.encoder,
-                ));
+                let mut reencoded = self
+                    .vocab
+                    .byte_pair_encode(&unstable_bytes[..unstable_bytes.len() - last_decoded.1]);
+                reencoded
+                    .extend(self.vocab.byte_pair_encode(
+                        &unstable_bytes[unstable_bytes.len() - last_decoded.1..],
+                    ));
                 completions.insert(reencoded);
             }
         }
@@ -619,6 +1367,15 @@ impl CoreBPE {
         encoder: HashMap<Vec<u8>, Rank>,
         special_tokens_encoder: HashMap<String, Rank>,
         pattern: &str,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Self::from_tables(Vocab::new(encoder), special_tokens_encoder, pattern)
+    }
+
+    /// Finishes construction from mergeable token tables.
+    fn from_tables(
+        vocab: Vocab,
+        special_tokens_encoder: HashMap<String, Rank>,
+        pattern: &str,
     ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
         let regex = Regex::new(pattern)?;
 
@@ -630,38 +1387,34 @@ impl CoreBPE {
             Regex::new(&parts.join("|"))?
         };
 
-        let decoder: HashMap<Rank, Vec<u8>> =
-            encoder.iter().map(|(k, v)| (*v, k.clone())).collect();
-
-        assert!(
-            encoder.len() == decoder.len(),
-            "Encoder and decoder must be of equal length. Encoder length: {}, decoder length: {}.\nMaybe you had duplicate token indices in your encoder?",
-            encoder.len(),
-            decoder.len()
-        );
-
         let special_tokens_decoder: HashMap<Rank, Vec<u8>> = special_tokens_encoder
             .iter()
             .map(|(k, v)| (*v, k.as_bytes().to_vec()))
             .collect();
 
-        // Clone because I don't know how to tell Rust I'm not going to change the map
-        let mut sorted_token_bytes: Vec<Vec<u8>> = encoder.keys().cloned().collect();
-        sorted_token_bytes.sort();
-
         Ok(Self {
-            encoder,
+            vocab,
             special_tokens_encoder,
-            decoder,
             special_tokens_decoder,
+            // Cloning a compiled regex is cheap, it shares the compiled program
             regex_tls: (0..MAX_NUM_THREADS).map(|_| regex.clone()).collect(),
             special_regex_tls: (0..MAX_NUM_THREADS)
                 .map(|_| special_regex.clone())
                 .collect(),
-            sorted_token_bytes,
         })
     }
 
+    /// Returns the mergeable ranks, e.g. for use with [`load::dump_tiktoken_bpe`]. For a
+    /// `CoreBPE` loaded from a snapshot, the map is built on each call.
+    pub fn mergeable_ranks(&self) -> Cow<'_, HashMap<Vec<u8>, Rank>> {
+        self.vocab.to_ranks()
+    }
+
+    /// Returns the regex pattern used to split text before BPE.
+    pub fn pattern(&self) -> &str {
+        self.regex_tls[0].as_str()
+    }
+
     pub fn special_tokens(&self) -> HashSet<&str> {
         self.special_tokens_encoder
             .keys()
@@ -670,17 +1423,20 @@ impl CoreBPE {
     }
 
     pub fn encode_with_special_tokens(&self, text: &str) -> Vec<Rank> {
-        let allowed_special = self.special_tokens();
-        self.encode(text, &allowed_special).unwrap().0
+        self.encode(text, AllowedSpecial::All).unwrap().0
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use fancy_regex::Regex;
+    use std::collections::HashSet;
+
     use rustc_hash::FxHashMap as HashMap;
 
-    use crate::{Rank, byte_pair_split};
+    use crate::{
+        AllowedSpecial, CheckedEncodeError, CoreBPE, DecodeMode, DisallowedSpecial, OffsetUnit,
+        Rank, Truncation, byte_pair_encode, byte_pair_split,
+    };
 
     fn setup_ranks() -> HashMap<Vec<u8>, Rank> {
         HashMap::from_iter([(b"ab".to_vec(), 0), (b"cd".to_vec(), 1)])
@@ -699,4 +1455,431 @@ mod tests {
         let res = byte_pair_split(b"abab", &ranks);
         assert_eq!(res, vec![b"ab", b"ab"]);
     }
+
+    fn setup_bpe() -> CoreBPE {
+        let mut encoder: HashMap<Vec<u8>, Rank> =
+            (0..=255u8).map(|b| (vec![b], b.into())).collect();
+        encoder.insert(b"ab".to_vec(), 256);
+        let special_tokens = HashMap::from_iter([("<|endoftext|>".to_string(), 257)]);
+        CoreBPE::new_internal(encoder, special_tokens, r"\w+|\s+|[^\w\s]+").unwrap()
+    }
+
+    #[test]
+    fn test_decode() {
+        let bpe = setup_bpe();
+
+        assert_eq!(bpe.decode_bytes(&[256, 257]).unwrap(), b"ab<|endoftext|>");
+        assert_eq!(bpe.decode_single_token_bytes(256).unwrap(), b"ab");
+        assert_eq!(
+            bpe.decode_tokens_bytes(&[97, 257]).unwrap(),
+            vec![b"a".as_slice(), b"<|endoftext|>"]
+        );
+        assert_eq!(bpe.decode_single_token_bytes(258).unwrap_err().token, 258);
+
+        // "é" is 0xc3 0xa9, so this is "ab" followed by a truncated "é"
+        let tokens = [256, 0xc3, 32];
+        assert_eq!(
+            bpe.decode(&tokens, DecodeMode::Replace).unwrap(),
+            "ab\u{fffd} "
+        );
+        assert_eq!(bpe.decode(&tokens, DecodeMode::Lossy).unwrap(), "ab ");
+        assert!(bpe.decode(&tokens, DecodeMode::Strict).is_err());
+        assert_eq!(
+            bpe.decode(&[256, 0xc3, 0xa9], DecodeMode::Strict).unwrap(),
+            "abé"
+        );
+        assert_eq!(
+            bpe.decode(&[258], DecodeMode::Replace).unwrap_err().message,
+            "Invalid token for decoding: 258"
+        );
+    }
+
+    #[test]
+    fn test_encode_checked() {
+        let bpe = setup_bpe();
+        let eot = HashSet::from(["<|endoftext|>"]);
+        let none = HashSet::new();
+        let text = "ab é<|endoftext|>";
+
+        let err = bpe.encode_checked(text, &none, &eot).unwrap_err();
+        let CheckedEncodeError::DisallowedSpecial { token, offset } = &err else {
+            panic!("{err}");
+        };
+        assert_eq!((token.as_str(), *offset), ("<|endoftext|>", 5));
+        assert_eq!(
+            err.to_string(),
+            "Encountered text corresponding to disallowed special token \"<|endoftext|>\" at byte 5"
+        );
+
+        // As in Python, a token that is both allowed and disallowed is an error
+        assert!(bpe.encode_checked(text, &eot, &eot).is_err());
+        assert_eq!(
+            bpe.encode_checked(text, &eot, &none).unwrap(),
+            vec![256, 32, 0xc3, 0xa9, 257]
+        );
+        assert_eq!(
+            bpe.encode_checked(text, &none, &none).unwrap(),
+            bpe.encode_ordinary(text)
+        );
+        assert_eq!(bpe.encode_checked("ab", &none, &eot).unwrap(), vec![256]);
+    }
+
+    #[test]
+    fn test_special_policies() {
+        let encoder: HashMap<Vec<u8>, Rank> = (0..=255u8).map(|b| (vec![b], b.into())).collect();
+        let special_tokens =
+            HashMap::from_iter([("<|eot|>".to_string(), 256), ("<|fim|>".to_string(), 257)]);
+        let bpe = CoreBPE::new_internal(encoder, special_tokens, r"\S+|\s+").unwrap();
+        let text = "<|eot|> <|fim|>";
+        let eot = HashSet::from(["<|eot|>"]);
+        let fim_as_text: Vec<Rank> = b"<|fim".iter().map(|&b| b.into()).collect();
+
+        let encode = |allowed_special: AllowedSpecial| bpe.encode(text, allowed_special).unwrap().0;
+        assert_eq!(encode(AllowedSpecial::All), vec![256, 32, 257]);
+        assert_eq!(encode(AllowedSpecial::None_), bpe.encode_ordinary(text));
+        assert_eq!(encode(AllowedSpecial::Only(&eot))[..2], [256, 32]);
+        assert_eq!(encode(AllowedSpecial::Only(&eot))[2..7], fim_as_text[..]);
+        assert_eq!(encode(AllowedSpecial::AllExcept(&eot))[8..], [257]);
+        assert_eq!(
+            bpe.encode(text, &eot).unwrap(),
+            bpe.encode(text, AllowedSpecial::Only(&eot)).unwrap()
+        );
+
+        // Everything not allowed is disallowed by default
+        let err = bpe
+            .encode_checked(text, &eot, DisallowedSpecial::default())
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            CheckedEncodeError::DisallowedSpecial { offset: 8, .. }
+        ));
+        assert!(
+            bpe.encode_checked(text, AllowedSpecial::All, DisallowedSpecial::All)
+                .is_ok()
+        );
+        // Encoding <|fim|> as text, while still disallowing the others
+        let fim = HashSet::from(["<|fim|>"]);
+        let err = bpe
+            .encode_checked(
+                text,
+                AllowedSpecial::None_,
+                DisallowedSpecial::AllExcept(&fim),
+            )
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            CheckedEncodeError::DisallowedSpecial { offset: 0, .. }
+        ));
+        let tokens = bpe
+            .encode_checked(text, &eot, DisallowedSpecial::AllExcept(&fim))
+            .unwrap();
+        assert_eq!(tokens[2..7], fim_as_text[..]);
+        assert!(
+            bpe.encode_checked(text, AllowedSpecial::None_, DisallowedSpecial::None_)
+                .is_ok()
+        );
+        // Disallowed strings need not be special tokens, and the first one found wins
+        let err = bpe
+            .encode_checked(text, AllowedSpecial::All, &HashSet::from(["fim"]))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            CheckedEncodeError::DisallowedSpecial { offset: 10, .. }
+        ));
+        let err = bpe
+            .encode_checked(text, AllowedSpecial::All, &HashSet::from(["<|fim|>", " "]))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            CheckedEncodeError::DisallowedSpecial { offset: 7, .. }
+        ));
+
+        let (tokens, completions) = bpe._encode_unstable_native(text, AllowedSpecial::All);
+        assert_eq!((tokens, completions.len()), (vec![256, 32, 257], 0));
+        assert_eq!(
+            bpe.encode_batch(&[text, "<|fim|>"], AllowedSpecial::All, 2)
+                .unwrap(),
+            vec![vec![256, 32, 257], vec![257]]
+        );
+    }
+
+    #[test]
+    fn test_encode_bytes() {
+        let bpe = setup_bpe();
+        assert_eq!(bpe.encode_bytes(b"ab ab"), bpe.encode_ordinary("ab ab"));
+        assert!(bpe.encode_bytes(b"").is_empty());
+
+        // Every truncation of a string of multi-byte characters
+        let text = "ab é😀 中ab";
+        for end in 0..=text.len() {
+            let bytes = &text.as_bytes()[..end];
+            let tokens = bpe.encode_bytes(bytes);
+            assert_eq!(bpe.decode_bytes(&tokens).unwrap(), bytes);
+            if let Ok(text) = std::str::from_utf8(bytes) {
+                assert_eq!(tokens, bpe.encode_ordinary(text));
+            }
+        }
+
+        // A truncated character is encoded along with the piece before it
+        let mut bytes = b"ab ab".to_vec();
+        bytes.push(0xc3);
+        let mut expected = bpe.encode_ordinary("ab ");
+        expected.extend(byte_pair_encode(b"ab\xc3", &bpe.mergeable_ranks()));
+        assert_eq!(bpe.encode_bytes(&bytes), expected);
+
+        // Invalid runs in the middle, of several bytes, and at the start
+        for bytes in [
+            b"ab\xff\xfe ab".as_slice(),
+            b"\xe4\xb8 ab\xc3\xa9\xf0\x9f\x98 \xe4\xb8\xad",
+            b"\x80ab  \xffab\xff",
+        ] {
+            let tokens = bpe.encode_bytes(bytes);
+            assert_eq!(bpe.decode_bytes(&tokens).unwrap(), bytes);
+        }
+        let tokens = bpe.encode_bytes(b"ab\xff ab");
+        assert_eq!(tokens[..2], [256, 0xff]);
+        assert_eq!(tokens[2..], bpe.encode_ordinary(" ab")[..]);
+    }
+
+    #[test]
+    fn test_encode_raw() {
+        let bpe = setup_bpe();
+        // No regex split, so "ab" merges across what would be piece boundaries
+        assert_eq!(
+            bpe.encode_raw(b"a!ab\xffab", 100),
+            vec![97, 33, 256, 0xff, 256]
+        );
+        assert_eq!(bpe.encode_raw(b"abab", 3), vec![256, 97, 98]);
+        assert_eq!(bpe.encode_raw(b"abab", 1), vec![97, 98, 97, 98]);
+        assert!(bpe.encode_raw(b"", 4).is_empty());
+
+        let mut state: u32 = 0x9e3779b9;
+        let bytes: Vec<u8> = (0..1000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                b"ab\xc3\xa9 "[state as usize % 5]
+            })
+            .collect();
+        for max_window in [1, 2, 7, 99, 100, 1000, 5000] {
+            let tokens = bpe.encode_raw(&bytes, max_window);
+            assert_eq!(bpe.decode_bytes(&tokens).unwrap(), bytes);
+            if max_window > 1 {
+                let expected: Vec<Rank> = bytes
+                    .chunks(max_window)
+                    .flat_map(|window| byte_pair_encode(window, &bpe.mergeable_ranks()))
+                    .collect();
+                assert_eq!(tokens, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode_with_offsets() {
+        let bpe = setup_bpe();
+        let text = "abé <|endoftext|>!";
+        let allowed_special = HashSet::from(["<|endoftext|>"]);
+        let offsets = bpe.encode_with_offsets(text, &allowed_special).unwrap();
+        assert_eq!(
+            offsets,
+            vec![
+                (256, 0, 2),
+                (0xc3, 2, 3),
+                (0xa9, 3, 4),
+                (32, 4, 5),
+                (257, 5, 18),
+                (33, 18, 19)
+            ]
+        );
+        let (tokens, _) = bpe.encode(text, &allowed_special).unwrap();
+        assert_eq!(
+            offsets
+                .iter()
+                .map(|&(token, _, _)| token)
+                .collect::<Vec<_>>(),
+            tokens
+        );
+
+        // Disallowed special tokens are encoded as text
+        let offsets = bpe.encode_with_offsets(text, &HashSet::new()).unwrap();
+        assert_eq!(offsets[4], (b'<'.into(), 5, 6));
+        assert_eq!(offsets.last(), Some(&(33, 18, 19)));
+    }
+
+    #[test]
+    fn test_encode_with_offsets_in() {
+        let bpe = setup_bpe();
+        // "é" is split into two tokens, and "😀" is 4 bytes, 2 UTF-16 code units and 1 char
+        let text = "abé 😀!";
+        let no_special = HashSet::new();
+        let spans = |unit| -> Vec<(usize, usize)> {
+            bpe.encode_with_offsets_in(text, &no_special, unit)
+                .unwrap()
+                .into_iter()
+                .map(|(_, start, end)| (start, end))
+                .collect()
+        };
+        assert_eq!(
+            spans(OffsetUnit::Bytes),
+            vec![
+                (0, 2),
+                (2, 3),
+                (3, 4),
+                (4, 5),
+                (5, 6),
+                (6, 7),
+                (7, 8),
+                (8, 9),
+                (9, 10)
+            ]
+        );
+        assert_eq!(
+            spans(OffsetUnit::Chars),
+            vec![
+                (0, 2),
+                (2, 3),
+                (2, 3),
+                (3, 4),
+                (4, 5),
+                (4, 5),
+                (4, 5),
+                (4, 5),
+                (5, 6)
+            ]
+        );
+        assert_eq!(
+            spans(OffsetUnit::Utf16),
+            vec![
+                (0, 2),
+                (2, 3),
+                (2, 3),
+                (3, 4),
+                (4, 6),
+                (4, 6),
+                (4, 6),
+                (4, 6),
+                (6, 7)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_count_tokens() {
+        let bpe = setup_bpe();
+        let text = "abab cab é <|endoftext|>";
+        let allowed_special = HashSet::from(["<|endoftext|>"]);
+        for allowed_special in [&allowed_special, &HashSet::new()] {
+            let (tokens, _) = bpe.encode(text, allowed_special).unwrap();
+            assert_eq!(
+                bpe.count_tokens(text, allowed_special).unwrap(),
+                tokens.len()
+            );
+        }
+
+        let n_tokens = bpe.encode_ordinary(text).len();
+        assert_eq!(bpe.count_tokens_up_to(text, n_tokens), Some(n_tokens));
+        assert_eq!(bpe.count_tokens_up_to(text, n_tokens - 1), None);
+        assert_eq!(bpe.count_tokens_up_to("", 0), Some(0));
+
+        // Long pieces take a different BPE path
+        let long = "ab".repeat(100) + "c";
+        assert_eq!(bpe.count_tokens_up_to(&long, 1000), Some(101));
+
+        // The merge buffers are reused across pieces of both kinds
+        let mixed = format!("{long} ab{long} cab ab");
+        assert_eq!(
+            bpe.count_tokens(&mixed, AllowedSpecial::None_).unwrap(),
+            bpe.encode_ordinary(&mixed).len()
+        );
+    }
+
+    #[test]
+    fn test_encode_truncated() {
+        let bpe = setup_bpe();
+        let no_special = HashSet::new();
+
+        let truncated = bpe
+            .encode_truncated("abab cab é", &no_special, 3, Truncation::Head)
+            .unwrap();
+        assert_eq!(truncated.tokens, vec![256, 256, 32]);
+        assert_eq!((truncated.head_end, truncated.tail_start), (5, 11));
+
+        // Neither end may split "é"
+        let truncated = bpe
+            .encode_truncated("abé", &no_special, 2, Truncation::Head)
+            .unwrap();
+        assert_eq!(truncated.tokens, vec![256]);
+        assert_eq!((truncated.head_end, truncated.tail_start), (2, 4));
+        let truncated = bpe
+            .encode_truncated("éab", &no_special, 2, Truncation::Tail)
+            .unwrap();
+        assert_eq!(truncated.tokens, vec![256]);
+        assert_eq!((truncated.head_end, truncated.tail_start), (0, 2));
+
+        let truncated = bpe
+            .encode_truncated("abab", &no_special, 2, Truncation::Tail)
+            .unwrap();
+        assert!(!truncated.is_truncated());
+        assert_eq!(truncated.tokens, vec![256, 256]);
+
+        let middle = Truncation::Middle { ellipsis: "~" };
+        let truncated = bpe
+            .encode_truncated("ab cd ef ab", &no_special, 4, middle)
+            .unwrap();
+        assert_eq!(truncated.tokens, vec![256, 32, b'~'.into(), 256]);
+        assert_eq!((truncated.head_end, truncated.tail_start), (3, 9));
+        assert!(
+            bpe.encode_truncated("ab cd ef ab", &no_special, 0, middle)
+                .is_err()
+        );
+
+        // Encoded on their own, the head and tail can take fewer tokens than in the full text,
+        // here because the space before "y" joins the run of spaces, but the ellipsis still
+        // goes between them
+        let mut encoder: HashMap<Vec<u8>, Rank> =
+            (0..=255u8).map(|b| (vec![b], b.into())).collect();
+        for spaces in [2, 4, 3, 7] {
+            let rank = encoder.len() as Rank;
+            encoder.insert(vec![b' '; spaces], rank);
+        }
+        let spaces_bpe =
+            CoreBPE::new_internal(encoder, HashMap::default(), r"\s+(?!\S)|\s+| ?\S+").unwrap();
+        let text = "x       yzwvu";
+        assert_eq!(spaces_bpe.encode_ordinary(text).len(), 9);
+        let truncated = spaces_bpe
+            .encode_truncated(text, &no_special, 8, middle)
+            .unwrap();
+        assert!(truncated.is_truncated());
+        assert_eq!((truncated.head_end, truncated.tail_start), (8, 9));
+        assert_eq!(
+            spaces_bpe
+                .decode(&truncated.tokens, DecodeMode::Strict)
+                .unwrap(),
+            "x       ~zwvu"
+        );
+
+        // The tokens are always the encoding of the kept text, within budget
+        let text = "abab <|endoftext|>cab é ab😀 ab";
+        let allowed_special = HashSet::from(["<|endoftext|>"]);
+        let n_tokens = bpe.count_tokens(text, &allowed_special).unwrap();
+        for truncation in [Truncation::Head, Truncation::Tail, middle] {
+            for max_tokens in 1..=n_tokens {
+                let truncated = bpe
+                    .encode_truncated(text, &allowed_special, max_tokens, truncation)
+                    .unwrap();
+                assert!(truncated.tokens.len() <= max_tokens);
+                let mut kept = text[..truncated.head_end].to_string();
+                if truncated.is_truncated() && truncation == middle {
+                    kept.push('~');
+                }
+                kept += &text[truncated.tail_start..];
+                assert_eq!(
+                    bpe.decode(&truncated.tokens, DecodeMode::Strict).unwrap(),
+                    kept
+                );
+                assert_eq!(truncated.is_truncated(), max_tokens < n_tokens);
+            }
+        }
+    }
 }
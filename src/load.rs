@@ -0,0 +1,804 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use rustc_hash::FxHashMap as HashMap;
+use sha2::{Digest, Sha256};
+
+use crate::{CoreBPE, Rank};
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    /// A malformed entry. `line` is 1-based.
+    Parse {
+        line: usize,
+        message: String,
+    },
+    HashMismatch {
+        expected: String,
+        actual: String,
+    },
+    /// The files parsed, but disagree with each other.
+    Inconsistent {
+        message: String,
+    },
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "Could not read BPE file: {e}"),
+            LoadError::Parse { line, message } => {
+                write!(f, "Error parsing line {line} of BPE file: {message}")
+            }
+            LoadError::HashMismatch { expected, actual } => write!(
+                f,
+                "Hash mismatch for BPE file (expected {expected}, got {actual}). \
+                 This may indicate a corrupted download."
+            ),
+            LoadError::Inconsistent { message } => write!(f, "Inconsistent BPE files: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+/// Returns whether the SHA-256 of `data` matches the hex digest `expected_hash`.
+pub fn check_hash(data: &[u8], expected_hash: &str) -> bool {
+    sha256_hex(data).eq_ignore_ascii_case(expected_hash)
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn read_checked<R: Read>(mut reader: R, expected_hash: Option<&str>) -> Result<Vec<u8>, LoadError> {
+    let mut contents = Vec::new();
+    reader.read_to_end(&mut contents)?;
+    if let Some(expected) = expected_hash {
+        let actual = sha256_hex(&contents);
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(LoadError::HashMismatch {
+                expected: expected.to_string(),
+                actual,
+            });
+        }
+    }
+    Ok(contents)
+}
+
+/// The cache directory used by `read_file_cached` in `tiktoken/load.py`.
+fn cache_dir() -> Option<PathBuf> {
+    let cache_dir = match std::env::var_os("TIKTOKEN_CACHE_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => match std::env::var_os("DATA_GYM_CACHE_DIR") {
+            Some(dir) => PathBuf::from(dir),
+            None => std::env::temp_dir().join("data-gym-cache"),
+        },
+    };
+    // An empty directory disables caching
+    (!cache_dir.as_os_str().is_empty()).then_some(cache_dir)
+}
+
+fn sha1_hex(data: &[u8]) -> String {
+    sha1::Sha1::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn find_cached(
+    blobpath: &str,
+    rank_file_dir: Option<&Path>,
+    cache_dir: Option<&Path>,
+) -> Option<PathBuf> {
+    let file_name = blobpath.rsplit('/').next().unwrap_or(blobpath);
+    let candidates = [
+        rank_file_dir.map(|dir| dir.join(file_name)),
+        cache_dir.map(|dir| dir.join(sha1_hex(blobpath.as_bytes()))),
+    ];
+    candidates.into_iter().flatten().find(|path| path.is_file())
+}
+
+/// Reads a file, which may be given by the URL it would be downloaded from.
+///
+/// This is the equivalent of `read_file_cached` in `tiktoken/load.py`, except that nothing is
+/// downloaded. Local paths are read directly. For URLs, `rank_file_dir` is checked first if
+/// given, then the cache directory that Python tiktoken populates (`TIKTOKEN_CACHE_DIR`,
+/// `DATA_GYM_CACHE_DIR` or `data-gym-cache` in the temp dir). Files in `rank_file_dir` are found
+/// by the last path component of their URL, e.g. `cl100k_base.tiktoken`, so a directory of rank
+/// files can be used as is.
+pub fn read_file_cached(
+    blobpath: &str,
+    expected_hash: Option<&str>,
+    rank_file_dir: Option<&Path>,
+) -> Result<Vec<u8>, LoadError> {
+    if !blobpath.contains("://") {
+        return read_checked(File::open(blobpath)?, expected_hash);
+    }
+    match find_cached(blobpath, rank_file_dir, cache_dir().as_deref()) {
+        Some(path) => read_checked(File::open(path)?, expected_hash),
+        None => Err(LoadError::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{blobpath} is not in the rank file directory or cache, and is not downloaded"),
+        ))),
+    }
+}
+
+/// Parses the contents of a `.tiktoken` file, i.e. lines of `<base64 token> <rank>`.
+///
+/// This is the equivalent of `load_tiktoken_bpe` in `tiktoken/load.py`. If `expected_hash` is
+/// given, the SHA-256 of the contents is checked before anything is parsed. Unlike in Python, a
+/// token or rank that appears on more than one line is an error, since `CoreBPE` needs a one to
+/// one mapping.
+pub fn load_tiktoken_bpe<R: Read>(
+    reader: R,
+    expected_hash: Option<&str>,
+) -> Result<HashMap<Vec<u8>, Rank>, LoadError> {
+    let contents = read_checked(reader, expected_hash)?;
+    let mut ret = HashMap::default();
+    // The line each rank was first seen on
+    let mut rank_lines: HashMap<Rank, usize> = HashMap::default();
+    for (i, line) in contents.split(|&b| b == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            continue;
+        }
+        let parse_error = |message: String| LoadError::Parse {
+            line: i + 1,
+            message,
+        };
+        let mut parts = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|part| !part.is_empty());
+        let (Some(token), Some(rank), None) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(parse_error(format!(
+                "expected `<base64 token> <rank>`, got {:?}",
+                bstr::BStr::new(line)
+            )));
+        };
+        let token = BASE64
+            .decode(token)
+            .map_err(|e| parse_error(format!("invalid base64 token: {e}")))?;
+        let rank = std::str::from_utf8(rank)
+            .ok()
+            .and_then(|rank| rank.parse::<Rank>().ok())
+            .ok_or_else(|| parse_error(format!("invalid rank {:?}", bstr::BStr::new(rank))))?;
+        if let Some(other) = rank_lines.insert(rank, i + 1) {
+            return Err(parse_error(format!(
+                "rank {rank} already used on line {other}"
+            )));
+        }
+        if ret.contains_key(&token) {
+            return Err(parse_error(format!(
+                "token {:?} appears more than once",
+                bstr::BStr::new(&token)
+            )));
+        }
+        ret.insert(token, rank);
+    }
+    Ok(ret)
+}
+
+/// Reads a `.tiktoken` file from disk. See [`load_tiktoken_bpe`].
+pub fn load_tiktoken_bpe_file(
+    path: impl AsRef<Path>,
+    expected_hash: Option<&str>,
+) -> Result<HashMap<Vec<u8>, Rank>, LoadError> {
+    load_tiktoken_bpe(File::open(path)?, expected_hash)
+}
+
+/// Writes mergeable ranks in the `.tiktoken` format, in rank order.
+///
+/// This is the equivalent of `dump_tiktoken_bpe` in `tiktoken/load.py`.
+pub fn dump_tiktoken_bpe<W: Write>(
+    bpe_ranks: &HashMap<Vec<u8>, Rank>,
+    mut writer: W,
+) -> io::Result<()> {
+    let mut sorted: Vec<(&Vec<u8>, &Rank)> = bpe_ranks.iter().collect();
+    sorted.sort_by_key(|&(_, rank)| *rank);
+    for (token, rank) in sorted {
+        writeln!(writer, "{} {}", BASE64.encode(token), rank)?;
+    }
+    writer.flush()
+}
+
+/// Writes a `.tiktoken` file to disk. See [`dump_tiktoken_bpe`].
+pub fn dump_tiktoken_bpe_file(
+    bpe_ranks: &HashMap<Vec<u8>, Rank>,
+    path: impl AsRef<Path>,
+) -> io::Result<()> {
+    dump_tiktoken_bpe(bpe_ranks, io::BufWriter::new(File::create(path)?))
+}
+
+/// Whether data gym uses a byte as its own stand-in, i.e. `chr(b).isprintable() and chr(b) != " "`.
+fn is_data_gym_printable(b: u8) -> bool {
+    matches!(b, 0x21..=0x7e | 0xa1..=0xac | 0xae..=0xff)
+}
+
+/// Returns the single byte tokens in the order data gym assigns them ranks: first the bytes that
+/// are printable (other than space), then all the others.
+fn data_gym_rank_to_byte() -> Vec<u8> {
+    let mut rank_to_byte: Vec<u8> = (0..=255).filter(|&b| is_data_gym_printable(b)).collect();
+    rank_to_byte.extend((0..=255).filter(|&b| !is_data_gym_printable(b)));
+    rank_to_byte
+}
+
+/// Returns the character data gym uses to stand in for each byte. Printable bytes stand for
+/// themselves, the rest are shifted past 255 so that every token is a printable string.
+pub(crate) fn data_gym_byte_to_char() -> [char; 256] {
+    let mut byte_to_char = ['\0'; 256];
+    let mut n = 0;
+    for b in 0..=255u8 {
+        byte_to_char[b as usize] = if is_data_gym_printable(b) {
+            char::from(b)
+        } else {
+            n += 1;
+            char::from_u32(255 + n).unwrap()
+        };
+    }
+    byte_to_char
+}
+
+pub(crate) fn encode_data_gym(byte_to_char: &[char; 256], token: &[u8]) -> String {
+    token.iter().map(|&b| byte_to_char[b as usize]).collect()
+}
+
+/// Reads the GPT-2 `vocab.bpe` and `encoder.json` pair into mergeable ranks.
+///
+/// This is the equivalent of `data_gym_to_mergeable_bpe_ranks` in `tiktoken/load.py`. Ranks come
+/// from the order of the merges in `vocab.bpe`, after the 256 single byte tokens. `encoder.json`
+/// is only used as a sanity check, since we rely on ranks matching merge priority. Its
+/// `<|endoftext|>` and `<|startoftext|>` entries are ignored. If `clobber_one_byte_tokens` is set,
+/// the ranks of single byte tokens are taken from `encoder.json` instead.
+pub fn data_gym_to_mergeable_bpe_ranks<R1: Read, R2: Read>(
+    vocab_bpe: R1,
+    encoder_json: R2,
+    vocab_bpe_hash: Option<&str>,
+    encoder_json_hash: Option<&str>,
+    clobber_one_byte_tokens: bool,
+) -> Result<HashMap<Vec<u8>, Rank>, LoadError> {
+    let char_to_byte: HashMap<char, u8> =
+        data_gym_byte_to_char().into_iter().zip(0..=255).collect();
+    let decode_data_gym = |value: &str| -> Option<Vec<u8>> {
+        value
+            .chars()
+            .map(|c| char_to_byte.get(&c).copied())
+            .collect()
+    };
+
+    // vocab_bpe contains the merges along with associated ranks
+    let vocab_bpe_contents = read_checked(vocab_bpe, vocab_bpe_hash)?;
+
+    // add the single byte tokens
+    // if clobber_one_byte_tokens is true, we'll replace these with ones from the encoder json
+    let mut bpe_ranks: HashMap<Vec<u8>, Rank> = data_gym_rank_to_byte()
+        .into_iter()
+        .zip(0..)
+        .map(|(b, rank)| (vec![b], rank))
+        .collect();
+
+    // add the merged tokens
+    // the first line is a version header
+    for (i, line) in vocab_bpe_contents
+        .split(|&b| b == b'\n')
+        .enumerate()
+        .skip(1)
+    {
+        if line.is_empty() {
+            continue;
+        }
+        let parse_error = |message: String| LoadError::Parse {
+            line: i + 1,
+            message: format!("vocab.bpe: {message}"),
+        };
+        let line =
+            std::str::from_utf8(line).map_err(|e| parse_error(format!("invalid UTF-8: {e}")))?;
+        let mut parts = line.split_whitespace();
+        let (Some(first), Some(second), None) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(parse_error(format!(
+                "expected `<first> <second>`, got {line:?}"
+            )));
+        };
+        let (Some(mut token), Some(second)) = (decode_data_gym(first), decode_data_gym(second))
+        else {
+            return Err(parse_error(format!(
+                "unexpected character in merge {line:?}"
+            )));
+        };
+        token.extend(second);
+        let rank = bpe_ranks.len() as Rank;
+        bpe_ranks.insert(token, rank);
+    }
+
+    // check that the encoder file matches the merges file
+    // this sanity check is important since tiktoken assumes that ranks are ordered the same
+    // as merge priority
+    let encoder_json_contents = read_checked(encoder_json, encoder_json_hash)?;
+    let encoder_json: HashMap<String, Rank> = serde_json::from_slice(&encoder_json_contents)
+        .map_err(|e| LoadError::Parse {
+            line: e.line(),
+            message: format!("encoder.json: {e}"),
+        })?;
+    let mut encoder_json_loaded = HashMap::default();
+    for (k, v) in encoder_json {
+        // drop these two special tokens if present, since they're not mergeable bpe tokens
+        if k == "<|endoftext|>" || k == "<|startoftext|>" {
+            continue;
+        }
+        let Some(token) = decode_data_gym(&k) else {
+            return Err(LoadError::Inconsistent {
+                message: format!("unexpected character in encoder.json token {k:?}"),
+            });
+        };
+        encoder_json_loaded.insert(token, v);
+    }
+
+    if clobber_one_byte_tokens {
+        for (k, &v) in &encoder_json_loaded {
+            if k.len() == 1 {
+                bpe_ranks.insert(k.clone(), v);
+            }
+        }
+    }
+
+    if let Some((token, &rank)) = bpe_ranks
+        .iter()
+        .find(|&(token, rank)| encoder_json_loaded.get(token) != Some(rank))
+    {
+        let message = match encoder_json_loaded.get(token) {
+            Some(other) => format!(
+                "token {:?} has rank {rank} in vocab.bpe but {other} in encoder.json",
+                bstr::BStr::new(token)
+            ),
+            None => format!(
+                "token {:?} from vocab.bpe is missing from encoder.json",
+                bstr::BStr::new(token)
+            ),
+        };
+        return Err(LoadError::Inconsistent { message });
+    }
+    if encoder_json_loaded.len() != bpe_ranks.len() {
+        let token = encoder_json_loaded
+            .keys()
+            .find(|token| !bpe_ranks.contains_key(*token))
+            .unwrap();
+        return Err(LoadError::Inconsistent {
+            message: format!(
+                "token {:?} from encoder.json is missing from vocab.bpe",
+                bstr::BStr::new(token)
+            ),
+        });
+    }
+
+    Ok(bpe_ranks)
+}
+
+/// Reads the GPT-2 `vocab.bpe` and `encoder.json` pair from disk. See
+/// [`data_gym_to_mergeable_bpe_ranks`].
+pub fn data_gym_to_mergeable_bpe_ranks_files(
+    vocab_bpe_file: impl AsRef<Path>,
+    encoder_json_file: impl AsRef<Path>,
+    vocab_bpe_hash: Option<&str>,
+    encoder_json_hash: Option<&str>,
+    clobber_one_byte_tokens: bool,
+) -> Result<HashMap<Vec<u8>, Rank>, LoadError> {
+    data_gym_to_mergeable_bpe_ranks(
+        File::open(vocab_bpe_file)?,
+        File::open(encoder_json_file)?,
+        vocab_bpe_hash,
+        encoder_json_hash,
+        clobber_one_byte_tokens,
+    )
+}
+
+/// The two parts a token was merged from, and the token's rank.
+pub(crate) type BpeMerge<'a> = (&'a [u8], &'a [u8], Rank);
+
+/// Recovers the merge that produced each multi-byte token, in rank order.
+///
+/// A token's merge is found by running BPE on its bytes using only tokens of lower rank, which
+/// must leave exactly two parts. This relies on ranks corresponding to merge priority.
+pub(crate) fn bpe_merges(bpe_ranks: &HashMap<Vec<u8>, Rank>) -> Result<Vec<BpeMerge<'_>>, String> {
+    let mut tokens: Vec<(&[u8], Rank)> = bpe_ranks
+        .iter()
+        .filter(|(token, _)| token.len() > 1)
+        .map(|(token, &rank)| (token.as_slice(), rank))
+        .collect();
+    tokens.sort_by_key(|&(_, rank)| rank);
+
+    let mut merges = Vec::with_capacity(tokens.len());
+    for (token, rank) in tokens {
+        // Boundaries between the current parts of `token`
+        let mut bounds: Vec<usize> = (0..=token.len()).collect();
+        loop {
+            let best = bounds
+                .windows(3)
+                .enumerate()
+                .filter_map(|(i, w)| {
+                    bpe_ranks
+                        .get(&token[w[0]..w[2]])
+                        .filter(|&&r| r < rank)
+                        .map(|&r| (r, i))
+                })
+                .min();
+            match best {
+                Some((_, i)) => {
+                    bounds.remove(i + 1);
+                }
+                None => break,
+            }
+        }
+        if bounds.len() != 3 {
+            return Err(format!(
+                "token {:?} with rank {rank} is not a merge of two lower ranked tokens",
+                bstr::BStr::new(token)
+            ));
+        }
+        merges.push((&token[..bounds[1]], &token[bounds[1]..], rank));
+    }
+    Ok(merges)
+}
+
+/// Writes mergeable ranks as the GPT-2 `vocab.bpe` and `encoder.json` pair.
+///
+/// This is the inverse of `data_gym_to_mergeable_bpe_ranks` in `tiktoken/load.py`. Bytes are
+/// written using the same printable stand-ins that function decodes. Since `vocab.bpe` only
+/// records merge order, all 256 single byte tokens must be present and the multi-byte tokens
+/// must have consecutive ranks starting at 256. Single byte tokens whose ranks differ from the
+/// data gym order need `clobber_one_byte_tokens` when loading.
+///
+/// `special_tokens` are only written to `encoder.json`. Note that the Python loader only
+/// tolerates `<|endoftext|>` and `<|startoftext|>` there.
+pub fn dump_data_gym<'a, W1: Write, W2: Write>(
+    bpe_ranks: &HashMap<Vec<u8>, Rank>,
+    special_tokens: impl IntoIterator<Item = (&'a str, Rank)>,
+    mut vocab_bpe_writer: W1,
+    mut encoder_json_writer: W2,
+) -> io::Result<()> {
+    let invalid_input = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+
+    if let Some(b) = (0..=255u8).find(|&b| !bpe_ranks.contains_key([b].as_slice())) {
+        return Err(invalid_input(format!("missing single byte token {b:#04x}")));
+    }
+    let merges = bpe_merges(bpe_ranks).map_err(invalid_input)?;
+    for (i, &(_, _, rank)) in merges.iter().enumerate() {
+        if rank as usize != 256 + i {
+            return Err(invalid_input(format!(
+                "multi-byte token ranks must be consecutive from 256, found rank {rank} at \
+                 position {}",
+                256 + i
+            )));
+        }
+    }
+
+    let byte_to_char = data_gym_byte_to_char();
+
+    writeln!(vocab_bpe_writer, "#version: 0.2")?;
+    for &(first, second, _) in &merges {
+        writeln!(
+            vocab_bpe_writer,
+            "{} {}",
+            encode_data_gym(&byte_to_char, first),
+            encode_data_gym(&byte_to_char, second)
+        )?;
+    }
+    vocab_bpe_writer.flush()?;
+
+    let mut sorted: Vec<(String, Rank)> = bpe_ranks
+        .iter()
+        .map(|(token, &rank)| (encode_data_gym(&byte_to_char, token), rank))
+        .chain(
+            special_tokens
+                .into_iter()
+                .map(|(s, rank)| (s.to_string(), rank)),
+        )
+        .collect();
+    sorted.sort_by_key(|&(_, rank)| rank);
+    write!(encoder_json_writer, "{{")?;
+    for (i, (token, rank)) in sorted.iter().enumerate() {
+        if i > 0 {
+            write!(encoder_json_writer, ", ")?;
+        }
+        serde_json::to_writer(&mut encoder_json_writer, token)?;
+        write!(encoder_json_writer, ": {rank}")?;
+    }
+    write!(encoder_json_writer, "}}")?;
+    encoder_json_writer.flush()
+}
+
+impl CoreBPE {
+    /// Creates a `CoreBPE` from the mergeable ranks in a `.tiktoken` file on disk. If
+    /// `expected_hash` is given, the file's SHA-256 is checked first.
+    pub fn from_tiktoken_file<SE>(
+        path: impl AsRef<Path>,
+        expected_hash: Option<&str>,
+        special_tokens_encoder: SE,
+        pattern: &str,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        SE: IntoIterator<Item = (String, Rank)>,
+    {
+        let encoder = load_tiktoken_bpe_file(path, expected_hash)?;
+        Self::new_internal(encoder, HashMap::from_iter(special_tokens_encoder), pattern)
+    }
+
+    /// Creates a `CoreBPE` from a GPT-2 `vocab.bpe` and `encoder.json` pair on disk.
+    pub fn from_data_gym_files<SE>(
+        vocab_bpe_file: impl AsRef<Path>,
+        encoder_json_file: impl AsRef<Path>,
+        special_tokens_encoder: SE,
+        pattern: &str,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        SE: IntoIterator<Item = (String, Rank)>,
+    {
+        let encoder = data_gym_to_mergeable_bpe_ranks_files(
+            vocab_bpe_file,
+            encoder_json_file,
+            None,
+            None,
+            false,
+        )?;
+        Self::new_internal(encoder, HashMap::from_iter(special_tokens_encoder), pattern)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup_ranks() -> HashMap<Vec<u8>, Rank> {
+        let mut ranks: HashMap<Vec<u8>, Rank> = data_gym_rank_to_byte()
+            .into_iter()
+            .enumerate()
+            .map(|(i, b)| (vec![b], i as Rank))
+            .collect();
+        for token in [b"ab".as_slice(), b"abc", b" \xff", b" \xffab"] {
+            ranks.insert(token.to_vec(), ranks.len() as Rank);
+        }
+        ranks
+    }
+
+    const CONTENTS: &[u8] = b"IQ== 0\nIg== 1\n\nIyE= 2\n";
+
+    #[test]
+    fn test_load_tiktoken_bpe() {
+        let ranks = load_tiktoken_bpe(CONTENTS, None).unwrap();
+        assert_eq!(ranks.len(), 3);
+        assert_eq!(ranks[b"!".as_slice()], 0);
+        assert_eq!(ranks[b"\"".as_slice()], 1);
+        assert_eq!(ranks[b"#!".as_slice()], 2);
+    }
+
+    #[test]
+    fn test_load_tiktoken_bpe_reports_line() {
+        let err = load_tiktoken_bpe(b"IQ== 0\n\nIg==\n".as_slice(), None).unwrap_err();
+        assert!(matches!(err, LoadError::Parse { line: 3, .. }), "{err}");
+
+        let err = load_tiktoken_bpe(b"IQ== 0\n!!!! 1\n".as_slice(), None).unwrap_err();
+        assert!(matches!(err, LoadError::Parse { line: 2, .. }), "{err}");
+
+        let err = load_tiktoken_bpe(b"IQ== -1\n".as_slice(), None).unwrap_err();
+        assert!(matches!(err, LoadError::Parse { line: 1, .. }), "{err}");
+    }
+
+    #[test]
+    fn test_load_tiktoken_bpe_duplicates() {
+        let err = load_tiktoken_bpe(b"IQ== 0\nIg== 1\nIyE= 1\n".as_slice(), None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error parsing line 3 of BPE file: rank 1 already used on line 2"
+        );
+
+        let err = load_tiktoken_bpe(b"IQ== 0\nIQ== 1\n".as_slice(), None).unwrap_err();
+        assert!(matches!(err, LoadError::Parse { line: 2, .. }), "{err}");
+    }
+
+    #[test]
+    fn test_from_tiktoken_file() {
+        let path =
+            std::env::temp_dir().join(format!("tiktoken-test-{}.tiktoken", std::process::id()));
+        dump_tiktoken_bpe_file(&setup_ranks(), &path).unwrap();
+        let hash = sha256_hex(&std::fs::read(&path).unwrap());
+        let special_tokens = [("<|endoftext|>".to_string(), 1000)];
+        let bpe =
+            CoreBPE::from_tiktoken_file(&path, Some(&hash), special_tokens.clone(), r"\S+|\s+")
+                .unwrap();
+        assert_eq!(
+            bpe.encode_ordinary("abc"),
+            vec![setup_ranks()[b"abc".as_slice()]]
+        );
+
+        let err = CoreBPE::from_tiktoken_file(&path, Some(&"0".repeat(64)), special_tokens, r"\S+")
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("Hash mismatch"), "{err}");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_tiktoken_bpe_hash() {
+        let hash = sha256_hex(CONTENTS);
+        assert!(check_hash(CONTENTS, &hash));
+        assert!(load_tiktoken_bpe(CONTENTS, Some(&hash)).is_ok());
+
+        let err = load_tiktoken_bpe(CONTENTS, Some(&"0".repeat(64))).unwrap_err();
+        assert!(matches!(err, LoadError::HashMismatch { .. }), "{err}");
+    }
+
+    #[test]
+    fn test_dump_tiktoken_bpe_roundtrip() {
+        let ranks = setup_ranks();
+        let mut buf = Vec::new();
+        dump_tiktoken_bpe(&ranks, &mut buf).unwrap();
+        assert!(buf.starts_with(b"IQ== 0\nIg== 1\n"));
+        assert_eq!(load_tiktoken_bpe(buf.as_slice(), None).unwrap(), ranks);
+    }
+
+    #[test]
+    fn test_dump_data_gym() {
+        let ranks = setup_ranks();
+        let mut vocab_bpe = Vec::new();
+        let mut encoder_json = Vec::new();
+        dump_data_gym(
+            &ranks,
+            [("<|endoftext|>", 260)],
+            &mut vocab_bpe,
+            &mut encoder_json,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(vocab_bpe).unwrap(),
+            "#version: 0.2\na b\nab c\nĠ ÿ\nĠÿ ab\n"
+        );
+        let encoder_json: serde_json::Value = serde_json::from_slice(&encoder_json).unwrap();
+        assert_eq!(encoder_json["!"], 0);
+        assert_eq!(encoder_json["Ā"], 188);
+        assert_eq!(encoder_json["Ġÿab"], 259);
+        assert_eq!(encoder_json["<|endoftext|>"], 260);
+    }
+
+    #[test]
+    fn test_dump_data_gym_requires_dense_merges() {
+        let mut ranks = setup_ranks();
+        ranks.insert(b"bc".to_vec(), 1000);
+        let err = dump_data_gym(&ranks, [], io::sink(), io::sink()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    fn dump_data_gym_to_vec(ranks: &HashMap<Vec<u8>, Rank>) -> (Vec<u8>, Vec<u8>) {
+        let mut vocab_bpe = Vec::new();
+        let mut encoder_json = Vec::new();
+        dump_data_gym(
+            ranks,
+            [("<|endoftext|>", ranks.len() as Rank)],
+            &mut vocab_bpe,
+            &mut encoder_json,
+        )
+        .unwrap();
+        (vocab_bpe, encoder_json)
+    }
+
+    #[test]
+    fn test_data_gym_roundtrip() {
+        let ranks = setup_ranks();
+        let (vocab_bpe, encoder_json) = dump_data_gym_to_vec(&ranks);
+        let loaded = data_gym_to_mergeable_bpe_ranks(
+            vocab_bpe.as_slice(),
+            encoder_json.as_slice(),
+            None,
+            None,
+            false,
+        )
+        .unwrap();
+        assert_eq!(loaded, ranks);
+    }
+
+    #[test]
+    fn test_data_gym_clobber_one_byte_tokens() {
+        let mut ranks = setup_ranks();
+        let bang = ranks[b"!".as_slice()];
+        let quote = ranks[b"\"".as_slice()];
+        ranks.insert(b"!".to_vec(), quote);
+        ranks.insert(b"\"".to_vec(), bang);
+        let (vocab_bpe, encoder_json) = dump_data_gym_to_vec(&ranks);
+
+        let err = data_gym_to_mergeable_bpe_ranks(
+            vocab_bpe.as_slice(),
+            encoder_json.as_slice(),
+            None,
+            None,
+            false,
+        )
+        .unwrap_err();
+        assert!(matches!(err, LoadError::Inconsistent { .. }), "{err}");
+
+        let loaded = data_gym_to_mergeable_bpe_ranks(
+            vocab_bpe.as_slice(),
+            encoder_json.as_slice(),
+            None,
+            None,
+            true,
+        )
+        .unwrap();
+        assert_eq!(loaded, ranks);
+    }
+
+    #[test]
+    fn test_data_gym_inconsistent() {
+        let (vocab_bpe, _) = dump_data_gym_to_vec(&setup_ranks());
+        let encoder_json = br#"{"!": 0}"#;
+        let err = data_gym_to_mergeable_bpe_ranks(
+            vocab_bpe.as_slice(),
+            encoder_json.as_slice(),
+            None,
+            None,
+            false,
+        )
+        .unwrap_err();
+        assert!(matches!(err, LoadError::Inconsistent { .. }), "{err}");
+
+        let err = data_gym_to_mergeable_bpe_ranks(
+            b"#version: 0.2\na b c\n".as_slice(),
+            encoder_json.as_slice(),
+            None,
+            None,
+            false,
+        )
+        .unwrap_err();
+        assert!(matches!(err, LoadError::Parse { line: 2, .. }), "{err}");
+    }
+
+    #[test]
+    fn test_find_cached() {
+        let dir = std::env::temp_dir().join(format!("tiktoken-test-{}", std::process::id()));
+        let rank_file_dir = dir.join("ranks");
+        let cache_dir = dir.join("cache");
+        std::fs::create_dir_all(&rank_file_dir).unwrap();
+        std::fs::create_dir_all(&cache_dir).unwrap();
+
+        let url = "https://openaipublic.blob.core.windows.net/encodings/r50k_base.tiktoken";
+        assert_eq!(
+            find_cached(url, Some(&rank_file_dir), Some(&cache_dir)),
+            None
+        );
+
+        // Python tiktoken names cache entries by the SHA-1 of the URL
+        let cached = cache_dir.join("0ea1e91bbb3a60f729a8dc8f777fd2fc07cd8df4");
+        std::fs::write(&cached, b"").unwrap();
+        assert_eq!(
+            find_cached(url, Some(&rank_file_dir), Some(&cache_dir)),
+            Some(cached.clone())
+        );
+
+        let local = rank_file_dir.join("r50k_base.tiktoken");
+        std::fs::write(&local, b"").unwrap();
+        assert_eq!(
+            find_cached(url, Some(&rank_file_dir), Some(&cache_dir)),
+            Some(local)
+        );
+        assert_eq!(find_cached(url, None, Some(&cache_dir)), Some(cached));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
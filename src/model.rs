@@ -0,0 +1,264 @@
+//! Mapping model names to encodings, mirroring `tiktoken/model.py`.
+
+use std::sync::Arc;
+
+use crate::encoding::Encoding;
+use crate::registry::{RegistryError, get_encoding};
+
+/// Checked in order, after [`MODEL_TO_ENCODING`].
+pub const MODEL_PREFIX_TO_ENCODING: &[(&str, &str)] = &[
+    ("o1-", "o200k_base"),
+    ("o3-", "o200k_base"),
+    ("o4-mini-", "o200k_base"),
+    // chat
+    ("gpt-5", "o200k_base"),
+    ("gpt-4.5-", "o200k_base"),
+    ("gpt-4.1-", "o200k_base"),
+    ("chatgpt-4o-", "o200k_base"),
+    ("gpt-4o-", "o200k_base"),         // e.g., gpt-4o-2024-05-13
+    ("gpt-4-", "cl100k_base"),         // e.g., gpt-4-0314, etc., plus gpt-4-32k
+    ("gpt-3.5-turbo-", "cl100k_base"), // e.g, gpt-3.5-turbo-0301, -0401, etc.
+    ("gpt-35-turbo-", "cl100k_base"),  // Azure deployment name
+    ("gpt-oss-", "o200k_harmony"),
+    // fine-tuned
+    ("ft:gpt-4o", "o200k_base"),
+    ("ft:gpt-4", "cl100k_base"),
+    ("ft:gpt-3.5-turbo", "cl100k_base"),
+    ("ft:davinci-002", "cl100k_base"),
+    ("ft:babbage-002", "cl100k_base"),
+];
+
+pub const MODEL_TO_ENCODING: &[(&str, &str)] = &[
+    // reasoning
+    ("o1", "o200k_base"),
+    ("o3", "o200k_base"),
+    ("o4-mini", "o200k_base"),
+    // chat
+    ("gpt-5", "o200k_base"),
+    ("gpt-4.1", "o200k_base"),
+    ("gpt-4o", "o200k_base"),
+    ("gpt-4", "cl100k_base"),
+    ("gpt-3.5-turbo", "cl100k_base"),
+    ("gpt-3.5", "cl100k_base"),      // Common shorthand
+    ("gpt-35-turbo", "cl100k_base"), // Azure deployment name
+    // base
+    ("davinci-002", "cl100k_base"),
+    ("babbage-002", "cl100k_base"),
+    // embeddings
+    ("text-embedding-ada-002", "cl100k_base"),
+    ("text-embedding-3-small", "cl100k_base"),
+    ("text-embedding-3-large", "cl100k_base"),
+    // DEPRECATED MODELS
+    // text (DEPRECATED)
+    ("text-davinci-003", "p50k_base"),
+    ("text-davinci-002", "p50k_base"),
+    ("text-davinci-001", "r50k_base"),
+    ("text-curie-001", "r50k_base"),
+    ("text-babbage-001", "r50k_base"),
+    ("text-ada-001", "r50k_base"),
+    ("davinci", "r50k_base"),
+    ("curie", "r50k_base"),
+    ("babbage", "r50k_base"),
+    ("ada", "r50k_base"),
+    // code (DEPRECATED)
+    ("code-davinci-002", "p50k_base"),
+    ("code-davinci-001", "p50k_base"),
+    ("code-cushman-002", "p50k_base"),
+    ("code-cushman-001", "p50k_base"),
+    ("davinci-codex", "p50k_base"),
+    ("cushman-codex", "p50k_base"),
+    // edit (DEPRECATED)
+    ("text-davinci-edit-001", "p50k_edit"),
+    ("code-davinci-edit-001", "p50k_edit"),
+    // old embeddings (DEPRECATED)
+    ("text-similarity-davinci-001", "r50k_base"),
+    ("text-similarity-curie-001", "r50k_base"),
+    ("text-similarity-babbage-001", "r50k_base"),
+    ("text-similarity-ada-001", "r50k_base"),
+    ("text-search-davinci-doc-001", "r50k_base"),
+    ("text-search-curie-doc-001", "r50k_base"),
+    ("text-search-babbage-doc-001", "r50k_base"),
+    ("text-search-ada-doc-001", "r50k_base"),
+    ("code-search-babbage-code-001", "r50k_base"),
+    ("code-search-ada-code-001", "r50k_base"),
+    // open source
+    ("gpt2", "gpt2"),
+    ("gpt-2", "gpt2"), // Maintains consistency with gpt-4
+];
+
+const MAX_NEAR_MISSES: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct UnknownModelError {
+    pub model_name: String,
+    /// Known model names that are similar to `model_name`, most similar first.
+    pub near_misses: Vec<&'static str>,
+}
+
+impl std::fmt::Display for UnknownModelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Could not automatically map {} to a tokeniser.",
+            self.model_name
+        )?;
+        if !self.near_misses.is_empty() {
+            write!(f, " Did you mean {}?", self.near_misses.join(", "))?;
+        }
+        write!(
+            f,
+            " Please use `get_encoding` to explicitly get the tokeniser you expect."
+        )
+    }
+}
+
+impl std::error::Error for UnknownModelError {}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut cur = vec![0; b.len() + 1];
+    for (i, ca) in a.chars().enumerate() {
+        cur[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != cb);
+            cur[j + 1] = substitution.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    prev[b.len()]
+}
+
+fn near_misses(model_name: &str) -> Vec<&'static str> {
+    let lowercase = model_name.to_lowercase();
+    // Allow roughly one edit per four characters, but always allow a typo or two
+    let max_distance = (lowercase.chars().count() / 4).max(2);
+    let mut candidates: Vec<(usize, &'static str)> = MODEL_TO_ENCODING
+        .iter()
+        .map(|&(name, _)| (edit_distance(&lowercase, name), name))
+        .filter(|&(distance, _)| distance <= max_distance)
+        .collect();
+    candidates.sort();
+    candidates
+        .into_iter()
+        .take(MAX_NEAR_MISSES)
+        .map(|(_, name)| name)
+        .collect()
+}
+
+/// Returns the name of the encoding used by a model.
+///
+/// Exact model names are checked first, then known prefixes. Prefix matching avoids needing
+/// library updates for every model version release, but can match non-existent models (e.g.
+/// `gpt-3.5-turbo-FAKE`).
+pub fn encoding_name_for_model(model_name: &str) -> Result<&'static str, UnknownModelError> {
+    if let Some(&(_, encoding_name)) = MODEL_TO_ENCODING
+        .iter()
+        .find(|&&(name, _)| name == model_name)
+    {
+        return Ok(encoding_name);
+    }
+    if let Some(&(_, encoding_name)) = MODEL_PREFIX_TO_ENCODING
+        .iter()
+        .find(|&&(prefix, _)| model_name.starts_with(prefix))
+    {
+        return Ok(encoding_name);
+    }
+    Err(UnknownModelError {
+        model_name: model_name.to_string(),
+        near_misses: near_misses(model_name),
+    })
+}
+
+/// Returns the encoding used by a model, from the [registry](crate::registry).
+pub fn encoding_for_model(
+    model_name: &str,
+) -> Result<Arc<Encoding>, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(Model::try_from(model_name)?.encoding()?)
+}
+
+/// A model name that is known to map to an encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Model<'a> {
+    name: &'a str,
+    encoding_name: &'static str,
+}
+
+impl<'a> Model<'a> {
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    pub fn encoding_name(&self) -> &'static str {
+        self.encoding_name
+    }
+
+    /// Gets the model's encoding from the [registry](crate::registry).
+    pub fn encoding(&self) -> Result<Arc<Encoding>, RegistryError> {
+        get_encoding(self.encoding_name)
+    }
+}
+
+impl<'a> TryFrom<&'a str> for Model<'a> {
+    type Error = UnknownModelError;
+
+    fn try_from(name: &'a str) -> Result<Self, Self::Error> {
+        Ok(Model {
+            name,
+            encoding_name: encoding_name_for_model(name)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoding_name_for_model() {
+        assert_eq!(encoding_name_for_model("gpt2").unwrap(), "gpt2");
+        assert_eq!(encoding_name_for_model("gpt-4").unwrap(), "cl100k_base");
+        assert_eq!(
+            encoding_name_for_model("gpt-4-32k-0314").unwrap(),
+            "cl100k_base"
+        );
+        assert_eq!(
+            encoding_name_for_model("gpt-4o-2024-05-13").unwrap(),
+            "o200k_base"
+        );
+        assert_eq!(
+            encoding_name_for_model("ft:gpt-4o-mini:org::id").unwrap(),
+            "o200k_base"
+        );
+        assert_eq!(
+            encoding_name_for_model("gpt-oss-120b").unwrap(),
+            "o200k_harmony"
+        );
+        assert_eq!(
+            encoding_name_for_model("text-davinci-edit-001").unwrap(),
+            "p50k_edit"
+        );
+
+        let model = Model::try_from("gpt-3.5-turbo-0301").unwrap();
+        assert_eq!(model.name(), "gpt-3.5-turbo-0301");
+        assert_eq!(model.encoding_name(), "cl100k_base");
+    }
+
+    #[test]
+    fn test_unknown_model() {
+        let err = Model::try_from("gtp-4o").unwrap_err();
+        assert_eq!(err.near_misses[0], "gpt-4o");
+        assert!(err.to_string().contains("Did you mean gpt-4o"), "{err}");
+
+        let err = encoding_name_for_model("GPT-4").unwrap_err();
+        assert_eq!(err.near_misses[0], "gpt-4");
+
+        let err = encoding_name_for_model("llama-3-70b-instruct").unwrap_err();
+        assert!(err.near_misses.is_empty());
+        assert_eq!(
+            err.to_string(),
+            "Could not automatically map llama-3-70b-instruct to a tokeniser. \
+             Please use `get_encoding` to explicitly get the tokeniser you expect."
+        );
+    }
+}
@@ -0,0 +1,240 @@
+//! The encodings defined in `tiktoken_ext/openai_public.py`.
+//!
+//! Rank files are found with [`read_file_cached`], so they must already be in the directory
+//! passed to the constructor or in the cache directory.
+
+use std::path::Path;
+
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::Rank;
+use crate::encoding::EncodingSpec;
+use crate::load::{
+    LoadError, data_gym_to_mergeable_bpe_ranks, load_tiktoken_bpe, read_file_cached,
+};
+
+pub const ENDOFTEXT: &str = "<|endoftext|>";
+pub const FIM_PREFIX: &str = "<|fim_prefix|>";
+pub const FIM_MIDDLE: &str = "<|fim_middle|>";
+pub const FIM_SUFFIX: &str = "<|fim_suffix|>";
+pub const ENDOFPROMPT: &str = "<|endofprompt|>";
+
+// The pattern in the original GPT-2 release is:
+// r"""'s|'t|'re|'ve|'m|'ll|'d| ?[\p{L}]+| ?[\p{N}]+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+"""
+// This is equivalent, but executes faster:
+pub const R50K_PAT_STR: &str =
+    r"'(?:[sdmt]|ll|ve|re)| ?\p{L}++| ?\p{N}++| ?[^\s\p{L}\p{N}]++|\s++$|\s+(?!\S)|\s";
+
+pub const CL100K_PAT_STR: &str = r"'(?i:[sdmt]|ll|ve|re)|[^\r\n\p{L}\p{N}]?+\p{L}++|\p{N}{1,3}+| ?[^\s\p{L}\p{N}]++[\r\n]*+|\s++$|\s*[\r\n]|\s+(?!\S)|\s";
+
+pub const O200K_PAT_STR: &str = concat!(
+    r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    "|",
+    r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    "|",
+    r"\p{N}{1,3}",
+    "|",
+    r" ?[^\s\p{L}\p{N}]+[\r\n/]*",
+    "|",
+    r"\s*[\r\n]+",
+    "|",
+    r"\s+(?!\S)",
+    "|",
+    r"\s+",
+);
+
+fn load_tiktoken_bpe_cached(
+    blobpath: &str,
+    expected_hash: &str,
+    rank_file_dir: Option<&Path>,
+) -> Result<HashMap<Vec<u8>, Rank>, LoadError> {
+    let contents = read_file_cached(blobpath, Some(expected_hash), rank_file_dir)?;
+    load_tiktoken_bpe(contents.as_slice(), None)
+}
+
+fn special_tokens<const N: usize>(tokens: [(&str, Rank); N]) -> HashMap<String, Rank> {
+    tokens
+        .into_iter()
+        .map(|(token, rank)| (token.to_string(), rank))
+        .collect()
+}
+
+pub fn gpt2(rank_file_dir: Option<&Path>) -> Result<EncodingSpec, LoadError> {
+    let vocab_bpe = read_file_cached(
+        "https://openaipublic.blob.core.windows.net/gpt-2/encodings/main/vocab.bpe",
+        Some("1ce1664773c50f3e0cc8842619a93edc4624525b728b188a9e0be33b7726adc5"),
+        rank_file_dir,
+    )?;
+    let encoder_json = read_file_cached(
+        "https://openaipublic.blob.core.windows.net/gpt-2/encodings/main/encoder.json",
+        Some("196139668be63f3b5d6574427317ae82f612a97c5d1cdaf36ed2256dbf636783"),
+        rank_file_dir,
+    )?;
+    let mergeable_ranks = data_gym_to_mergeable_bpe_ranks(
+        vocab_bpe.as_slice(),
+        encoder_json.as_slice(),
+        None,
+        None,
+        false,
+    )?;
+    Ok(EncodingSpec {
+        name: "gpt2".to_string(),
+        pat_str: R50K_PAT_STR.to_string(),
+        mergeable_ranks,
+        special_tokens: special_tokens([(ENDOFTEXT, 50256)]),
+        explicit_n_vocab: Some(50257),
+    })
+}
+
+pub fn r50k_base(rank_file_dir: Option<&Path>) -> Result<EncodingSpec, LoadError> {
+    let mergeable_ranks = load_tiktoken_bpe_cached(
+        "https://openaipublic.blob.core.windows.net/encodings/r50k_base.tiktoken",
+        "306cd27f03c1a714eca7108e03d66b7dc042abe8c258b44c199a7ed9838dd930",
+        rank_file_dir,
+    )?;
+    Ok(EncodingSpec {
+        name: "r50k_base".to_string(),
+        pat_str: R50K_PAT_STR.to_string(),
+        mergeable_ranks,
+        special_tokens: special_tokens([(ENDOFTEXT, 50256)]),
+        explicit_n_vocab: Some(50257),
+    })
+}
+
+pub fn p50k_base(rank_file_dir: Option<&Path>) -> Result<EncodingSpec, LoadError> {
+    let mergeable_ranks = load_tiktoken_bpe_cached(
+        "https://openaipublic.blob.core.windows.net/encodings/p50k_base.tiktoken",
+        "94b5ca7dff4d00767bc256fdd1b27e5b17361d7b8a5f968547f9f23eb70d2069",
+        rank_file_dir,
+    )?;
+    Ok(EncodingSpec {
+        name: "p50k_base".to_string(),
+        pat_str: R50K_PAT_STR.to_string(),
+        mergeable_ranks,
+        special_tokens: special_tokens([(ENDOFTEXT, 50256)]),
+        explicit_n_vocab: Some(50281),
+    })
+}
+
+pub fn p50k_edit(rank_file_dir: Option<&Path>) -> Result<EncodingSpec, LoadError> {
+    let mergeable_ranks = load_tiktoken_bpe_cached(
+        "https://openaipublic.blob.core.windows.net/encodings/p50k_base.tiktoken",
+        "94b5ca7dff4d00767bc256fdd1b27e5b17361d7b8a5f968547f9f23eb70d2069",
+        rank_file_dir,
+    )?;
+    Ok(EncodingSpec {
+        name: "p50k_edit".to_string(),
+        pat_str: R50K_PAT_STR.to_string(),
+        mergeable_ranks,
+        special_tokens: special_tokens([
+            (ENDOFTEXT, 50256),
+            (FIM_PREFIX, 50281),
+            (FIM_MIDDLE, 50282),
+            (FIM_SUFFIX, 50283),
+        ]),
+        explicit_n_vocab: None,
+    })
+}
+
+pub fn cl100k_base(rank_file_dir: Option<&Path>) -> Result<EncodingSpec, LoadError> {
+    let mergeable_ranks = load_tiktoken_bpe_cached(
+        "https://openaipublic.blob.core.windows.net/encodings/cl100k_base.tiktoken",
+        "223921b76ee99bde995b7ff738513eef100fb51d18c93597a113bcffe865b2a7",
+        rank_file_dir,
+    )?;
+    Ok(EncodingSpec {
+        name: "cl100k_base".to_string(),
+        pat_str: CL100K_PAT_STR.to_string(),
+        mergeable_ranks,
+        special_tokens: special_tokens([
+            (ENDOFTEXT, 100257),
+            (FIM_PREFIX, 100258),
+            (FIM_MIDDLE, 100259),
+            (FIM_SUFFIX, 100260),
+            (ENDOFPROMPT, 100276),
+        ]),
+        explicit_n_vocab: None,
+    })
+}
+
+pub fn o200k_base(rank_file_dir: Option<&Path>) -> Result<EncodingSpec, LoadError> {
+    let mergeable_ranks = load_tiktoken_bpe_cached(
+        "https://openaipublic.blob.core.windows.net/encodings/o200k_base.tiktoken",
+        "446a9538cb6c348e3516120d7c08b09f57c36495e2acfffe59a5bf8b0cfb1a2d",
+        rank_file_dir,
+    )?;
+    Ok(EncodingSpec {
+        name: "o200k_base".to_string(),
+        pat_str: O200K_PAT_STR.to_string(),
+        mergeable_ranks,
+        special_tokens: special_tokens([(ENDOFTEXT, 199999), (ENDOFPROMPT, 200018)]),
+        explicit_n_vocab: None,
+    })
+}
+
+fn o200k_harmony_special_tokens() -> HashMap<String, Rank> {
+    let mut tokens = special_tokens([
+        (ENDOFTEXT, 199999),
+        (ENDOFPROMPT, 200018),
+        ("<|startoftext|>", 199998),
+        ("<|reserved_200000|>", 200000),
+        ("<|reserved_200001|>", 200001),
+        ("<|return|>", 200002),
+        ("<|constrain|>", 200003),
+        ("<|reserved_200004|>", 200004),
+        ("<|channel|>", 200005),
+        ("<|start|>", 200006),
+        ("<|end|>", 200007),
+        ("<|message|>", 200008),
+        ("<|reserved_200009|>", 200009),
+        ("<|reserved_200010|>", 200010),
+        ("<|reserved_200011|>", 200011),
+        ("<|call|>", 200012),
+    ]);
+    tokens.extend((200013..201088).map(|i| (format!("<|reserved_{i}|>"), i)));
+    tokens
+}
+
+pub fn o200k_harmony(rank_file_dir: Option<&Path>) -> Result<EncodingSpec, LoadError> {
+    let base_enc = o200k_base(rank_file_dir)?;
+    Ok(EncodingSpec {
+        name: "o200k_harmony".to_string(),
+        special_tokens: o200k_harmony_special_tokens(),
+        ..base_enc
+    })
+}
+
+/// Takes a directory to look for rank files in before the cache directory.
+pub type OpenAIPublicConstructor = fn(Option<&Path>) -> Result<EncodingSpec, LoadError>;
+
+pub const ENCODING_CONSTRUCTORS: &[(&str, OpenAIPublicConstructor)] = &[
+    ("gpt2", gpt2),
+    ("r50k_base", r50k_base),
+    ("p50k_base", p50k_base),
+    ("p50k_edit", p50k_edit),
+    ("cl100k_base", cl100k_base),
+    ("o200k_base", o200k_base),
+    ("o200k_harmony", o200k_harmony),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patterns_compile() {
+        for pattern in [R50K_PAT_STR, CL100K_PAT_STR, O200K_PAT_STR] {
+            fancy_regex::Regex::new(pattern).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_o200k_harmony_special_tokens() {
+        let tokens = o200k_harmony_special_tokens();
+        // <|endofprompt|> and <|reserved_200018|> share a rank, as in openai_public.py
+        assert_eq!(tokens.len(), 201088 - 199998 + 1);
+        assert_eq!(tokens["<|endofprompt|>"], 200018);
+        assert_eq!(tokens["<|reserved_200018|>"], 200018);
+        assert_eq!(tokens["<|reserved_201087|>"], 201087);
+    }
+}
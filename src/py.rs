@@ -1,14 +1,18 @@
 use std::collections::HashSet;
 
 use pyo3::{
-    IntoPyObjectExt, PyResult, exceptions,
+    Borrowed, IntoPyObjectExt, PyResult, exceptions,
     prelude::*,
     pybacked::PyBackedStr,
-    types::{PyBytes, PyList},
+    types::{PyBytes, PyList, PyString},
 };
 use rustc_hash::FxHashMap as HashMap;
 
-use crate::{CoreBPE, Rank, byte_pair_encode};
+use crate::streaming::Utf8Buffer;
+use crate::{
+    AllowedSpecial, CheckedEncodeError, CoreBPE, DisallowedSpecial, EncodeError, OffsetCursor,
+    OffsetUnit, Rank, Truncation,
+};
 
 #[pymethods]
 impl CoreBPE {
@@ -48,70 +52,229 @@ impl CoreBPE {
         })
     }
 
-    fn encode_to_tiktoken_buffer(
+    #[pyo3(name = "encode_checked")]
+    fn py_encode_checked(
         &self,
         py: Python,
         text: &str,
-        allowed_special: HashSet<PyBackedStr>,
-    ) -> PyResult<Py<PyAny>> {
-        let tokens_res = py.detach(|| {
-            let allowed_special: HashSet<&str> =
-                allowed_special.iter().map(|s| s.as_ref()).collect();
-            self.encode(text, &allowed_special)
-        });
+        allowed_special: SpecialTokens,
+        disallowed_special: SpecialTokens,
+    ) -> PyResult<Vec<Rank>> {
+        py.detach(|| {
+            self.checked(
+                &[text],
+                &allowed_special,
+                &disallowed_special,
+                |allowed_special| Ok(self.encode(text, allowed_special)?.0),
+            )
+        })
+        .map_err(|e| checked_encode_error(py, e))
+    }
+
+    #[pyo3(name = "encode_ordinary_batch")]
+    fn py_encode_ordinary_batch(
+        &self,
+        py: Python,
+        texts: Vec<PyBackedStr>,
+        num_threads: usize,
+    ) -> Vec<Vec<Rank>> {
+        py.detach(|| self.encode_ordinary_batch(&texts, num_threads))
+    }
+
+    #[pyo3(name = "encode_batch")]
+    fn py_encode_batch(
+        &self,
+        py: Python,
+        texts: Vec<PyBackedStr>,
+        allowed_special: SpecialTokens,
+        disallowed_special: SpecialTokens,
+        num_threads: usize,
+    ) -> PyResult<Vec<Vec<Rank>>> {
+        py.detach(|| {
+            self.checked(
+                &texts,
+                &allowed_special,
+                &disallowed_special,
+                |allowed_special| self.encode_batch(&texts, allowed_special, num_threads),
+            )
+        })
+        .map_err(|e| checked_encode_error(py, e))
+    }
+
+    #[pyo3(name = "encode_parallel")]
+    fn py_encode_parallel(
+        &self,
+        py: Python,
+        text: &str,
+        allowed_special: SpecialTokens,
+        disallowed_special: SpecialTokens,
+        num_threads: usize,
+    ) -> PyResult<Vec<Rank>> {
+        py.detach(|| {
+            self.checked(
+                &[text],
+                &allowed_special,
+                &disallowed_special,
+                |allowed_special| self.encode_parallel(text, allowed_special, num_threads),
+            )
+        })
+        .map_err(|e| checked_encode_error(py, e))
+    }
+
+    #[pyo3(name = "encode_with_offsets")]
+    fn py_encode_with_offsets(
+        &self,
+        py: Python,
+        text: &str,
+        allowed_special: SpecialTokens,
+        disallowed_special: SpecialTokens,
+        unit: &str,
+    ) -> PyResult<Vec<(Rank, usize, usize)>> {
+        let unit = match unit {
+            "bytes" => OffsetUnit::Bytes,
+            "chars" => OffsetUnit::Chars,
+            "utf16" => OffsetUnit::Utf16,
+            _ => {
+                return Err(PyErr::new::<exceptions::PyValueError, _>(format!(
+                    "Unknown offset unit {unit:?}, expected \"bytes\", \"chars\" or \"utf16\""
+                )));
+            }
+        };
+        py.detach(|| {
+            self.checked(
+                &[text],
+                &allowed_special,
+                &disallowed_special,
+                |allowed_special| self.encode_with_offsets_in(text, allowed_special, unit),
+            )
+        })
+        .map_err(|e| checked_encode_error(py, e))
+    }
 
-        let tokens = match tokens_res {
-            Ok((tokens, _)) => tokens,
-            Err(e) => return Err(PyErr::new::<exceptions::PyValueError, _>(e.message)),
+    /// Returns the tokens and the kept head end and tail start, as indices into `text`.
+    #[pyo3(name = "encode_truncated")]
+    #[allow(clippy::too_many_arguments)]
+    fn py_encode_truncated(
+        &self,
+        py: Python,
+        text: &str,
+        allowed_special: SpecialTokens,
+        disallowed_special: SpecialTokens,
+        max_tokens: usize,
+        keep: &str,
+        ellipsis: &str,
+    ) -> PyResult<(Vec<Rank>, usize, usize)> {
+        let truncation = match keep {
+            "head" => Truncation::Head,
+            "tail" => Truncation::Tail,
+            "middle" => Truncation::Middle { ellipsis },
+            _ => {
+                return Err(PyErr::new::<exceptions::PyValueError, _>(format!(
+                    "Unknown truncation {keep:?}, expected \"head\", \"tail\" or \"middle\""
+                )));
+            }
         };
+        py.detach(|| {
+            let truncated = self.checked(
+                &[text],
+                &allowed_special,
+                &disallowed_special,
+                |allowed_special| {
+                    self.encode_truncated(text, allowed_special, max_tokens, truncation)
+                },
+            )?;
+            let head_end = text[..truncated.head_end].chars().count();
+            let tail_start = head_end
+                + text[truncated.head_end..truncated.tail_start]
+                    .chars()
+                    .count();
+            Ok((truncated.tokens, head_end, tail_start))
+        })
+        .map_err(|e| checked_encode_error(py, e))
+    }
+
+    /// Returns `(tokens, start, end)` for each chunk, with indices into `text`.
+    #[pyo3(name = "chunk")]
+    fn py_chunk(
+        &self,
+        py: Python,
+        text: &str,
+        max_tokens: usize,
+        overlap: usize,
+    ) -> PyResult<Vec<(Vec<Rank>, usize, usize)>> {
+        if max_tokens == 0 || overlap >= max_tokens {
+            return Err(PyErr::new::<exceptions::PyValueError, _>(
+                "max_tokens must be positive and greater than overlap",
+            ));
+        }
+        Ok(py.detach(|| {
+            let cursor = || OffsetCursor {
+                text,
+                unit: OffsetUnit::Chars,
+                byte: 0,
+                offset: 0,
+            };
+            // Chunks can overlap, so starts and ends each need their own cursor
+            let (mut starts, mut ends) = (cursor(), cursor());
+            self.chunk(text, max_tokens, overlap)
+                .into_iter()
+                .map(|chunk| {
+                    let start = starts.floor(chunk.start);
+                    let end = ends.floor(chunk.end);
+                    (chunk.tokens, start, end)
+                })
+                .collect()
+        }))
+    }
+
+    #[pyo3(name = "count_tokens")]
+    fn py_count_tokens(
+        &self,
+        py: Python,
+        text: &str,
+        allowed_special: SpecialTokens,
+        disallowed_special: SpecialTokens,
+    ) -> PyResult<usize> {
+        py.detach(|| {
+            self.checked(
+                &[text],
+                &allowed_special,
+                &disallowed_special,
+                |allowed_special| self.count_tokens(text, allowed_special),
+            )
+        })
+        .map_err(|e| checked_encode_error(py, e))
+    }
+
+    #[pyo3(name = "count_tokens_up_to")]
+    fn py_count_tokens_up_to(&self, py: Python, text: &str, limit: usize) -> Option<usize> {
+        py.detach(|| self.count_tokens_up_to(text, limit))
+    }
+
+    fn encode_to_tiktoken_buffer(
+        &self,
+        py: Python,
+        text: &str,
+        allowed_special: SpecialTokens,
+        disallowed_special: SpecialTokens,
+    ) -> PyResult<Py<PyAny>> {
+        let tokens = py
+            .detach(|| {
+                self.checked(
+                    &[text],
+                    &allowed_special,
+                    &disallowed_special,
+                    |allowed_special| Ok(self.encode(text, allowed_special)?.0),
+                )
+            })
+            .map_err(|e| checked_encode_error(py, e))?;
 
         let buffer = TiktokenBuffer { tokens };
         buffer.into_py_any(py)
     }
 
     fn _encode_bytes(&self, py: Python, bytes: &[u8]) -> Vec<Rank> {
-        py.detach(|| {
-            match std::str::from_utf8(bytes) {
-                // Straightforward case
-                Ok(text) => self.encode_ordinary(text),
-                // Oops, don't actually have UTF-8. But we need to do the regex splitting in
-                // Unicode space, so we make our best guess at where we would have splits
-                Err(e) => {
-                    let text = unsafe { std::str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) };
-                    let (tokens, last_piece_token_len) =
-                        self.encode(text, &HashSet::new()).unwrap();
-                    let (mut tokens, last_piece_token_len) =
-                        self._increase_last_piece_token_len(tokens, last_piece_token_len);
-
-                    let mut unstable_bytes;
-                    if !tokens.is_empty() && last_piece_token_len > 0 {
-                        // Lop off the tokens from the last piece and run BPE on the remaining bytes
-                        // This likely matches what models see better, e.g. if you assume we're
-                        // dealing with truncated UTF-8 bytes.
-                        // Niche, but note this may not be correct if we'd have had a regex
-                        // split between the valid UTF-8 and the invalid bytes.
-                        unstable_bytes = self
-                            .decode_bytes(&tokens[tokens.len() - last_piece_token_len..])
-                            .unwrap();
-                        unstable_bytes.extend_from_slice(&bytes[e.valid_up_to()..]);
-
-                        tokens.truncate(tokens.len() - last_piece_token_len);
-                    } else {
-                        unstable_bytes = bytes[e.valid_up_to()..].to_vec();
-                    }
-
-                    if !unstable_bytes.is_empty() {
-                        match self.encoder.get(&unstable_bytes) {
-                            Some(token) => tokens.push(*token),
-                            None => {
-                                tokens.extend(&byte_pair_encode(&unstable_bytes, &self.encoder))
-                            }
-                        }
-                    }
-                    tokens
-                }
-            }
-        })
+        py.detach(|| self.encode_bytes(bytes))
     }
 
     #[pyo3(name = "encode_with_unstable")]
@@ -119,19 +282,25 @@ impl CoreBPE {
         &self,
         py: Python,
         text: &str,
-        allowed_special: HashSet<PyBackedStr>,
+        allowed_special: SpecialTokens,
+        disallowed_special: SpecialTokens,
     ) -> PyResult<(Vec<Rank>, Py<PyList>)> {
-        let (tokens, completions): (Vec<Rank>, HashSet<Vec<Rank>>) = py.detach(|| {
-            let allowed_special: HashSet<&str> =
-                allowed_special.iter().map(|s| s.as_ref()).collect();
-            self._encode_unstable_native(text, &allowed_special)
-        });
+        let (tokens, completions): (Vec<Rank>, HashSet<Vec<Rank>>) = py
+            .detach(|| {
+                self.checked(
+                    &[text],
+                    &allowed_special,
+                    &disallowed_special,
+                    |allowed_special| Ok(self._encode_unstable_native(text, allowed_special)),
+                )
+            })
+            .map_err(|e| checked_encode_error(py, e))?;
         let py_completions = PyList::new(py, completions.into_iter())?;
         Ok((tokens, py_completions.into()))
     }
 
     fn encode_single_token(&self, piece: &[u8]) -> PyResult<Rank> {
-        if let Some(token) = self.encoder.get(piece).copied() {
+        if let Some(token) = self.vocab.get(piece) {
             return Ok(token);
         }
         if let Ok(piece_str) = std::str::from_utf8(piece) {
@@ -143,10 +312,10 @@ impl CoreBPE {
     }
 
     fn encode_single_piece(&self, piece: &[u8]) -> Vec<Rank> {
-        if let Some(token) = self.encoder.get(piece) {
-            return vec![*token];
+        if let Some(token) = self.vocab.get(piece) {
+            return vec![token];
         }
-        byte_pair_encode(piece, &self.encoder)
+        self.vocab.byte_pair_encode(piece)
     }
 
     // ====================
@@ -161,14 +330,28 @@ impl CoreBPE {
         }
     }
 
-    fn decode_single_token_bytes(&self, py: Python, token: Rank) -> PyResult<Py<PyBytes>> {
-        if let Some(bytes) = self.decoder.get(&token) {
-            return Ok(PyBytes::new(py, bytes).into());
+    #[pyo3(name = "decode_bytes_batch")]
+    fn py_decode_bytes_batch(
+        &self,
+        py: Python,
+        batch: Vec<Vec<Rank>>,
+        num_threads: usize,
+    ) -> PyResult<Vec<Py<PyBytes>>> {
+        match py.detach(|| self.decode_bytes_batch(&batch, num_threads)) {
+            Ok(batch) => Ok(batch
+                .iter()
+                .map(|bytes| PyBytes::new(py, bytes).into())
+                .collect()),
+            Err(e) => Err(pyo3::exceptions::PyKeyError::new_err(format!("{}", e))),
         }
-        if let Some(bytes) = self.special_tokens_decoder.get(&token) {
-            return Ok(PyBytes::new(py, bytes).into());
+    }
+
+    #[pyo3(name = "decode_single_token_bytes")]
+    fn py_decode_single_token_bytes(&self, py: Python, token: Rank) -> PyResult<Py<PyBytes>> {
+        match self.decode_single_token_bytes(token) {
+            Ok(bytes) => Ok(PyBytes::new(py, bytes).into()),
+            Err(e) => Err(PyErr::new::<exceptions::PyKeyError, _>(e.token.to_string())),
         }
-        Err(PyErr::new::<exceptions::PyKeyError, _>(token.to_string()))
     }
 
     // ====================
@@ -176,13 +359,102 @@ impl CoreBPE {
     // ====================
 
     fn token_byte_values(&self, py: Python) -> Vec<Py<PyBytes>> {
-        self.sorted_token_bytes
-            .iter()
-            .map(|x| PyBytes::new(py, x).into())
+        self.vocab
+            .sorted()
+            .map(|(x, _)| PyBytes::new(py, x).into())
             .collect()
     }
 }
 
+/// `allowed_special` or `disallowed_special` as passed from Python: "all", or a collection of
+/// tokens. Taking "all" as is spares converting a set of every special token on each call.
+enum SpecialTokens {
+    All,
+    Only(HashSet<PyBackedStr>),
+}
+
+impl<'a, 'py> FromPyObject<'a, 'py> for SpecialTokens {
+    type Error = PyErr;
+
+    fn extract(obj: Borrowed<'a, 'py, PyAny>) -> PyResult<Self> {
+        if let Ok(s) = obj.cast::<PyString>() {
+            // A string is iterable too, but as characters rather than tokens
+            let s = s.to_str()?;
+            if s == "all" {
+                return Ok(SpecialTokens::All);
+            }
+            return Err(PyErr::new::<exceptions::PyTypeError, _>(format!(
+                "expected \"all\" or a collection of special tokens, got the string {s:?}"
+            )));
+        }
+        obj.try_iter()?
+            .map(|token| token?.extract())
+            .collect::<PyResult<_>>()
+            .map(SpecialTokens::Only)
+    }
+}
+
+impl SpecialTokens {
+    /// The tokens to borrow an `AllowedSpecial::Only` or `DisallowedSpecial::Only` from, or
+    /// `None` for "all".
+    fn strs(&self) -> Option<HashSet<&str>> {
+        match self {
+            SpecialTokens::All => None,
+            SpecialTokens::Only(tokens) => Some(tokens.iter().map(|s| s.as_ref()).collect()),
+        }
+    }
+}
+
+impl CoreBPE {
+    /// Checks `texts` for disallowed special tokens like `encode_checked`, then runs `encode`
+    /// with the allowed special tokens.
+    fn checked<T>(
+        &self,
+        texts: &[impl AsRef<str>],
+        allowed_special: &SpecialTokens,
+        disallowed_special: &SpecialTokens,
+        encode: impl FnOnce(AllowedSpecial) -> Result<T, EncodeError>,
+    ) -> Result<T, CheckedEncodeError> {
+        let allowed = allowed_special.strs();
+        let allowed_special = match &allowed {
+            None => AllowedSpecial::All,
+            Some(tokens) => AllowedSpecial::Only(tokens),
+        };
+        let disallowed = disallowed_special.strs();
+        let disallowed_special = match &disallowed {
+            None => DisallowedSpecial::All,
+            Some(tokens) => DisallowedSpecial::Only(tokens),
+        };
+        for text in texts {
+            self.check_disallowed_special(text.as_ref(), allowed_special, disallowed_special)?;
+        }
+        Ok(encode(allowed_special)?)
+    }
+}
+
+/// Converts a `CheckedEncodeError`, with the message `encode` has always raised for a disallowed
+/// special token.
+fn checked_encode_error(py: Python, e: CheckedEncodeError) -> PyErr {
+    match e {
+        CheckedEncodeError::DisallowedSpecial { token, .. } => {
+            let token = match PyString::new(py, &token).repr() {
+                Ok(token) => token,
+                Err(e) => return e,
+            };
+            PyErr::new::<exceptions::PyValueError, _>(format!(
+                "Encountered text corresponding to disallowed special token {token}.\n\
+                 If you want this text to be encoded as a special token, pass it to \
+                 `allowed_special`, e.g. `allowed_special={{{token}, ...}}`.\n\
+                 If you want this text to be encoded as normal text, disable the check for \
+                 this token by passing `disallowed_special=(enc.special_tokens_set - \
+                 {{{token}}})`.\n\
+                 To disable this check for all special tokens, pass `disallowed_special=()`.\n"
+            ))
+        }
+        CheckedEncodeError::Encode(e) => PyErr::new::<exceptions::PyValueError, _>(e.message),
+    }
+}
+
 #[pyclass(frozen)]
 struct TiktokenBuffer {
     tokens: Vec<Rank>,
@@ -248,8 +520,43 @@ impl TiktokenBuffer {
     }
 }
 
+/// Decodes tokens one at a time, holding back bytes of incomplete characters.
+#[pyclass(name = "StreamingDecoder")]
+struct PyStreamingDecoder {
+    core_bpe: Py<CoreBPE>,
+    buffer: Utf8Buffer,
+}
+
+#[pymethods]
+impl PyStreamingDecoder {
+    #[new]
+    fn py_new(core_bpe: Py<CoreBPE>) -> Self {
+        PyStreamingDecoder {
+            core_bpe,
+            buffer: Utf8Buffer::default(),
+        }
+    }
+
+    fn push(&mut self, token: Rank) -> PyResult<String> {
+        let bytes = match self.core_bpe.get().decode_single_token_bytes(token) {
+            Ok(bytes) => bytes,
+            Err(e) => return Err(PyErr::new::<exceptions::PyKeyError, _>(e.token.to_string())),
+        };
+        let mut out = String::new();
+        self.buffer.push(bytes, &mut out);
+        Ok(out)
+    }
+
+    fn finish(&mut self) -> String {
+        let mut out = String::new();
+        self.buffer.finish(&mut out);
+        out
+    }
+}
+
 #[pymodule(gil_used = false)]
 fn _tiktoken(_py: Python, m: &Bound<PyModule>) -> PyResult<()> {
     m.add_class::<CoreBPE>()?;
+    m.add_class::<PyStreamingDecoder>()?;
     Ok(())
 }
@@ -0,0 +1,228 @@
+//! A registry of named encodings, mirroring `tiktoken/registry.py`.
+//!
+//! The encodings in [`crate::openai_public`] are always available. Their rank files are looked up
+//! in the cache directory, or first in a directory passed to [`get_encoding_from_dir`]. Other
+//! crates can add their own encodings with [`register_encoding`], which plays the role of a
+//! `tiktoken_ext` plugin module.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::encoding::{Encoding, EncodingSpec};
+use crate::openai_public;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+/// Takes the directory to look for rank files in, which only the built-in encodings use.
+type Constructor = Arc<dyn Fn(Option<&Path>) -> Result<EncodingSpec, BoxError> + Send + Sync>;
+
+#[derive(Debug)]
+pub enum RegistryError {
+    Unknown {
+        name: String,
+    },
+    Duplicate {
+        name: String,
+    },
+    /// The constructor, or building the `Encoding` from its result, failed.
+    Construct {
+        name: String,
+        source: BoxError,
+    },
+}
+
+impl std::fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RegistryError::Unknown { name } => write!(
+                f,
+                "Unknown encoding {name}. Available encodings: {}",
+                list_encoding_names().join(", ")
+            ),
+            RegistryError::Duplicate { name } => write!(f, "Duplicate encoding name {name}"),
+            RegistryError::Construct { name, source } => {
+                write!(f, "Could not construct encoding {name}: {source}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RegistryError::Construct { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+struct Registry {
+    /// In registration order, so that `list_encoding_names` is stable.
+    constructors: Vec<(String, Constructor)>,
+    /// By name and rank file directory.
+    encodings: HashMap<(String, Option<PathBuf>), Arc<Encoding>>,
+}
+
+static REGISTRY: LazyLock<Mutex<Registry>> = LazyLock::new(|| {
+    let constructors = openai_public::ENCODING_CONSTRUCTORS
+        .iter()
+        .map(|&(name, constructor)| {
+            let constructor: Constructor =
+                Arc::new(move |rank_file_dir| Ok(constructor(rank_file_dir)?));
+            (name.to_string(), constructor)
+        })
+        .collect();
+    Mutex::new(Registry {
+        constructors,
+        encodings: HashMap::default(),
+    })
+});
+
+/// Registers an encoding that [`get_encoding`] can construct by name.
+///
+/// The constructor is called at most once per successful [`get_encoding`], the first time the
+/// encoding is requested. Names must be unique, including against the built-in encodings.
+pub fn register_encoding<F>(name: &str, constructor: F) -> Result<(), RegistryError>
+where
+    F: Fn() -> Result<EncodingSpec, BoxError> + Send + Sync + 'static,
+{
+    let mut registry = REGISTRY.lock().unwrap();
+    if registry.constructors.iter().any(|(n, _)| n == name) {
+        return Err(RegistryError::Duplicate {
+            name: name.to_string(),
+        });
+    }
+    registry
+        .constructors
+        .push((name.to_string(), Arc::new(move |_| constructor())));
+    Ok(())
+}
+
+/// Returns the encoding with the given name, constructing it on first use.
+pub fn get_encoding(encoding_name: &str) -> Result<Arc<Encoding>, RegistryError> {
+    get_encoding_impl(encoding_name, None)
+}
+
+/// Like [`get_encoding`], but the rank files of the built-in encodings are looked for in
+/// `rank_file_dir` before the cache directory, as in
+/// [`read_file_cached`](crate::load::read_file_cached). Encodings are constructed once per
+/// directory.
+pub fn get_encoding_from_dir(
+    encoding_name: &str,
+    rank_file_dir: &Path,
+) -> Result<Arc<Encoding>, RegistryError> {
+    get_encoding_impl(encoding_name, Some(rank_file_dir))
+}
+
+fn get_encoding_impl(
+    encoding_name: &str,
+    rank_file_dir: Option<&Path>,
+) -> Result<Arc<Encoding>, RegistryError> {
+    let key = (
+        encoding_name.to_string(),
+        rank_file_dir.map(Path::to_path_buf),
+    );
+    let constructor = {
+        let registry = REGISTRY.lock().unwrap();
+        if let Some(enc) = registry.encodings.get(&key) {
+            return Ok(enc.clone());
+        }
+        let Some((_, constructor)) = registry
+            .constructors
+            .iter()
+            .find(|(n, _)| n == encoding_name)
+        else {
+            return Err(RegistryError::Unknown {
+                name: encoding_name.to_string(),
+            });
+        };
+        constructor.clone()
+    };
+
+    // Construct without holding the lock, since loading can be slow and constructors may
+    // themselves call `get_encoding`. If two threads race, the first to finish wins.
+    let construct_error = |source| RegistryError::Construct {
+        name: encoding_name.to_string(),
+        source,
+    };
+    let enc = constructor(rank_file_dir)
+        .and_then(|spec| Ok(Encoding::new(spec)?))
+        .map_err(construct_error)?;
+
+    let mut registry = REGISTRY.lock().unwrap();
+    Ok(registry
+        .encodings
+        .entry(key)
+        .or_insert_with(|| Arc::new(enc))
+        .clone())
+}
+
+/// Returns the names of all registered encodings, built-in ones first.
+pub fn list_encoding_names() -> Vec<String> {
+    let registry = REGISTRY.lock().unwrap();
+    registry
+        .constructors
+        .iter()
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Rank;
+
+    fn test_spec() -> Result<EncodingSpec, BoxError> {
+        Ok(EncodingSpec {
+            name: "registry_test".to_string(),
+            pat_str: r"\w+|\s+|[^\w\s]+".to_string(),
+            mergeable_ranks: (0..=255u8).map(|b| (vec![b], Rank::from(b))).collect(),
+            special_tokens: HashMap::default(),
+            explicit_n_vocab: None,
+        })
+    }
+
+    #[test]
+    fn test_register_encoding() {
+        register_encoding("registry_test", test_spec).unwrap();
+        let err = register_encoding("registry_test", test_spec).unwrap_err();
+        assert!(matches!(err, RegistryError::Duplicate { .. }));
+        assert!(matches!(
+            register_encoding("cl100k_base", test_spec),
+            Err(RegistryError::Duplicate { .. })
+        ));
+
+        let names = list_encoding_names();
+        assert_eq!(names[0], "gpt2");
+        assert!(names.contains(&"o200k_harmony".to_string()));
+        assert!(names.contains(&"registry_test".to_string()));
+
+        let enc = get_encoding("registry_test").unwrap();
+        assert_eq!(enc.name(), "registry_test");
+        assert_eq!(enc.encode_ordinary("hi"), vec![104, 105]);
+        assert!(Arc::ptr_eq(&enc, &get_encoding("registry_test").unwrap()));
+
+        let dir = Path::new("ranks");
+        let enc_from_dir = get_encoding_from_dir("registry_test", dir).unwrap();
+        assert!(!Arc::ptr_eq(&enc, &enc_from_dir));
+        assert!(Arc::ptr_eq(
+            &enc_from_dir,
+            &get_encoding_from_dir("registry_test", dir).unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_get_encoding_errors() {
+        let err = get_encoding("no_such_encoding").err().unwrap();
+        assert!(matches!(err, RegistryError::Unknown { .. }));
+        assert!(err.to_string().contains("cl100k_base"), "{err}");
+
+        register_encoding("registry_test_failing", || Err("no ranks".into())).unwrap();
+        let err = get_encoding("registry_test_failing").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Could not construct encoding registry_test_failing: no ranks"
+        );
+    }
+}
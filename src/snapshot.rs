@@ -0,0 +1,635 @@
+//! A binary snapshot of a `CoreBPE`, for fast startup.
+//!
+//! Building a `CoreBPE` from mergeable ranks involves hashing every token several times and
+//! sorting all token bytes. A snapshot stores the finished tables instead: the tokens sorted by
+//! their bytes, a table from rank to token, and a hash index from token bytes to rank. A `CoreBPE`
+//! loaded from a snapshot looks tokens up in those tables in place, so loading allocates nothing
+//! per mergeable token. With the `mmap` feature, [`CoreBPE::from_snapshot_file`] memory maps the
+//! file rather than reading it into a buffer. Loading still verifies the checksum and checks that
+//! the tables are consistent, compiles the regexes and builds the (small) special token maps.
+//!
+//! The layout is little-endian throughout:
+//!
+//! ```text
+//! magic            8 bytes, b"tiktoken"
+//! version          u32
+//! reserved         u32
+//! checksum         u64, `hash` of everything after this field
+//! n_tokens         u32
+//! n_special        u32
+//! token_bytes_len  u32
+//! special_len      u32
+//! pattern_len      u32
+//! rank_table_len   u32, one more than the largest rank
+//! n_slots          u32, a power of two larger than n_tokens
+//! reserved         u32
+//! tokens           n_tokens x (offset u32, len u32, rank u32), sorted by token bytes
+//! rank table       rank_table_len x u32, the index in tokens of each rank, or u32::MAX
+//! token index      n_slots x (offset u32, len u32, rank u32), a copy of a token, or zeros
+//! special tokens   n_special x (len u32, rank u32)
+//! token tags       n_slots bytes, the tag of the token in each slot, or 0 for an empty slot
+//! token bytes      token_bytes_len bytes
+//! special bytes    special_len bytes
+//! pattern          pattern_len bytes, UTF-8
+//! ```
+//!
+//! The token index is an open addressing hash table with linear probing. A token's first slot is
+//! the top bits of its `hash`, and its tag the next 7 bits with the high bit set. Probing reads the
+//! (small) tags first, so most lookups that miss never read the index or the token.
+
+use std::io::{self, Write};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::vocab::Vocab;
+use crate::{CoreBPE, Rank};
+
+const MAGIC: &[u8; 8] = b"tiktoken";
+pub const SNAPSHOT_VERSION: u32 = 2;
+const HEADER_LEN: usize = 56;
+const CHECKSUM_END: usize = 24;
+const TOKEN_ENTRY_LEN: usize = 12;
+const NO_TOKEN: u32 = u32::MAX;
+
+#[derive(Debug, Clone)]
+pub struct SnapshotError {
+    pub message: String,
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Could not load snapshot: {}", self.message)
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+fn error(message: impl Into<String>) -> SnapshotError {
+    SnapshotError {
+        message: message.into(),
+    }
+}
+
+/// The hash used for the checksum and the token index. It is part of the format, so it is
+/// spelled out here rather than taken from a hasher whose output could change between versions.
+#[inline]
+fn hash(data: &[u8]) -> u64 {
+    const K: u64 = 0x517cc1b727220a95;
+    let mut hash = data.len() as u64;
+    let mut words = data.chunks_exact(8);
+    for word in &mut words {
+        hash = (hash.rotate_left(5) ^ u64::from_le_bytes(word.try_into().unwrap())).wrapping_mul(K);
+    }
+    let rest = words.remainder();
+    if !rest.is_empty() {
+        // Padding the last bytes into a word this way is much faster than copying them into a
+        // buffer, and lookups mostly hash short tokens
+        let word = rest
+            .iter()
+            .enumerate()
+            .fold(0, |word, (i, &byte)| word | u64::from(byte) << (8 * i));
+        hash = (hash.rotate_left(5) ^ word).wrapping_mul(K);
+    }
+    hash
+}
+
+/// The tag of a token with this hash in the token index, which is never 0. The low bits of
+/// `hash` only depend on the first bytes, so this takes the bits just below the slot instead.
+#[inline]
+fn tag_of(hash: u64, slot_shift: u32) -> u8 {
+    (hash >> (slot_shift - 7)) as u8 | 0x80
+}
+
+fn u32_len(len: usize) -> io::Result<u32> {
+    u32::try_from(len).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too large"))
+}
+
+#[inline]
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+/// Reads little-endian `u32`s from the front of a slice.
+struct Cursor<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.data.len() < len {
+            return Err(error("unexpected end of data"));
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> Result<usize, SnapshotError> {
+        Ok(self.u32()? as usize)
+    }
+}
+
+/// The bytes of a snapshot, which a `CoreBPE` loaded from it keeps for its whole life.
+enum SnapshotData {
+    Bytes(Vec<u8>),
+    #[cfg(feature = "mmap")]
+    Mmap(memmap2::Mmap),
+}
+
+impl Deref for SnapshotData {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        match self {
+            SnapshotData::Bytes(data) => data,
+            #[cfg(feature = "mmap")]
+            SnapshotData::Mmap(data) => data,
+        }
+    }
+}
+
+/// The mergeable token tables of a snapshot, read in place.
+#[derive(Clone)]
+pub(crate) struct SnapshotVocab {
+    data: Arc<SnapshotData>,
+    n_tokens: usize,
+    // Byte offsets of the tables in `data`
+    tokens: usize,
+    rank_table: usize,
+    rank_table_len: usize,
+    slots: usize,
+    tags: usize,
+    slot_mask: usize,
+    slot_shift: u32,
+    token_bytes: usize,
+}
+
+impl SnapshotVocab {
+    /// The bytes and rank of the `i`th token in order of bytes.
+    #[inline]
+    fn entry<'a>(&self, data: &'a [u8], i: usize) -> (&'a [u8], Rank) {
+        self.entry_at(data, self.tokens + i * TOKEN_ENTRY_LEN)
+    }
+
+    /// The bytes and rank of the token entry at `pos`.
+    #[inline]
+    fn entry_at<'a>(&self, data: &'a [u8], pos: usize) -> (&'a [u8], Rank) {
+        let offset = u32_at(data, pos) as usize;
+        let len = u32_at(data, pos + 4) as usize;
+        let start = self.token_bytes + offset;
+        (&data[start..start + len], u32_at(data, pos + 8))
+    }
+
+    #[inline]
+    pub(crate) fn get(&self, token: &[u8]) -> Option<Rank> {
+        let data: &[u8] = &self.data;
+        let hash = hash(token);
+        let mut slot = (hash >> self.slot_shift) as usize;
+        loop {
+            let tag = data[self.tags + slot];
+            if tag == 0 {
+                return None;
+            }
+            if tag == tag_of(hash, self.slot_shift) {
+                let (bytes, rank) = self.entry_at(data, self.slots + slot * TOKEN_ENTRY_LEN);
+                if bytes == token {
+                    return Some(rank);
+                }
+            }
+            slot = (slot + 1) & self.slot_mask;
+        }
+    }
+
+    #[inline]
+    pub(crate) fn token(&self, rank: Rank) -> Option<&[u8]> {
+        let rank = rank as usize;
+        if rank >= self.rank_table_len {
+            return None;
+        }
+        let data: &[u8] = &self.data;
+        match u32_at(data, self.rank_table + rank * 4) {
+            NO_TOKEN => None,
+            index => Some(self.entry(data, index as usize).0),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.n_tokens
+    }
+
+    pub(crate) fn sorted_token(&self, i: usize) -> &[u8] {
+        self.entry(&self.data, i).0
+    }
+}
+
+impl CoreBPE {
+    /// Writes a snapshot that [`CoreBPE::from_snapshot`] can load.
+    ///
+    /// Ranks must be mostly contiguous, as they are in any trained vocabulary, since the snapshot
+    /// has an entry for every rank up to the largest.
+    pub fn write_snapshot<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let tokens: Vec<(&[u8], Rank)> = self.vocab.sorted().collect();
+        let mut special_tokens: Vec<(&String, &Rank)> =
+            self.special_tokens_encoder.iter().collect();
+        special_tokens.sort_by_key(|&(token, rank)| (*rank, token));
+        let pattern = self.pattern();
+
+        let rank_table_len = tokens
+            .iter()
+            .map(|&(_, rank)| rank as usize + 1)
+            .max()
+            .unwrap_or(0);
+        if rank_table_len > 2 * tokens.len().max(256) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ranks are too sparse for a snapshot",
+            ));
+        }
+        let mut rank_table = vec![NO_TOKEN; rank_table_len];
+        for (i, &(_, rank)) in tokens.iter().enumerate() {
+            rank_table[rank as usize] = i as u32;
+        }
+
+        let n_slots = (2 * tokens.len()).max(8).next_power_of_two();
+        let slot_shift = 64 - n_slots.trailing_zeros();
+        let mut slots = vec![None; n_slots];
+        let mut tags = vec![0u8; n_slots];
+        for (i, &(token, _)) in tokens.iter().enumerate() {
+            let hash = hash(token);
+            let mut slot = (hash >> slot_shift) as usize;
+            while slots[slot].is_some() {
+                slot = (slot + 1) & (n_slots - 1);
+            }
+            slots[slot] = Some(i);
+            tags[slot] = tag_of(hash, slot_shift);
+        }
+
+        let mut body = Vec::new();
+        body.extend(u32_len(tokens.len())?.to_le_bytes());
+        body.extend(u32_len(special_tokens.len())?.to_le_bytes());
+        let token_bytes_len: usize = tokens.iter().map(|(token, _)| token.len()).sum();
+        body.extend(u32_len(token_bytes_len)?.to_le_bytes());
+        let special_len: usize = special_tokens.iter().map(|(s, _)| s.len()).sum();
+        body.extend(u32_len(special_len)?.to_le_bytes());
+        body.extend(u32_len(pattern.len())?.to_le_bytes());
+        body.extend(u32_len(rank_table_len)?.to_le_bytes());
+        body.extend(u32_len(n_slots)?.to_le_bytes());
+        body.extend(0u32.to_le_bytes());
+        let mut entries = Vec::with_capacity(tokens.len());
+        let mut offset = 0;
+        for &(token, rank) in &tokens {
+            let mut entry = [0; TOKEN_ENTRY_LEN];
+            entry[..4].copy_from_slice(&u32_len(offset)?.to_le_bytes());
+            entry[4..8].copy_from_slice(&u32_len(token.len())?.to_le_bytes());
+            entry[8..].copy_from_slice(&rank.to_le_bytes());
+            entries.push(entry);
+            offset += token.len();
+        }
+        body.extend(entries.iter().flatten());
+        for index in rank_table {
+            body.extend(index.to_le_bytes());
+        }
+        for slot in slots {
+            body.extend(slot.map_or([0; TOKEN_ENTRY_LEN], |i| entries[i]));
+        }
+        for &(token, rank) in &special_tokens {
+            body.extend(u32_len(token.len())?.to_le_bytes());
+            body.extend(rank.to_le_bytes());
+        }
+        body.extend(tags);
+        for &(token, _) in &tokens {
+            body.extend(token);
+        }
+        for &(token, _) in &special_tokens {
+            body.extend(token.as_bytes());
+        }
+        body.extend(pattern.as_bytes());
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(&hash(&body).to_le_bytes())?;
+        writer.write_all(&body)?;
+        writer.flush()
+    }
+
+    /// Writes a snapshot to disk. See [`CoreBPE::write_snapshot`].
+    pub fn write_snapshot_file(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let file = std::fs::File::create(path)?;
+        self.write_snapshot(io::BufWriter::new(file))
+    }
+
+    /// Loads a snapshot written by [`CoreBPE::write_snapshot`], copying `data` into a single
+    /// buffer that the `CoreBPE` reads its tables from.
+    ///
+    /// The version and checksum are verified before anything else is read.
+    pub fn from_snapshot(data: &[u8]) -> Result<Self, SnapshotError> {
+        Self::from_snapshot_data(SnapshotData::Bytes(data.to_vec()))
+    }
+
+    fn from_snapshot_data(data: SnapshotData) -> Result<Self, SnapshotError> {
+        if data.len() < HEADER_LEN || &data[..8] != MAGIC {
+            return Err(error("not a tiktoken snapshot"));
+        }
+        let version = u32_at(&data, 8);
+        if version != SNAPSHOT_VERSION {
+            return Err(error(format!(
+                "unsupported version {version}, expected {SNAPSHOT_VERSION}"
+            )));
+        }
+        let checksum = u64::from_le_bytes(data[16..CHECKSUM_END].try_into().unwrap());
+        if hash(&data[CHECKSUM_END..]) != checksum {
+            return Err(error("checksum mismatch"));
+        }
+
+        let mut header = Cursor {
+            data: &data[CHECKSUM_END..HEADER_LEN],
+        };
+        let n_tokens = header.len()?;
+        let n_special = header.len()?;
+        let token_bytes_len = header.len()?;
+        let special_len = header.len()?;
+        let pattern_len = header.len()?;
+        let rank_table_len = header.len()?;
+        let n_slots = header.len()?;
+        if !n_slots.is_power_of_two() || n_slots < 2 || n_slots <= n_tokens {
+            return Err(error("invalid token index size"));
+        }
+
+        let mut rest = Cursor {
+            data: &data[HEADER_LEN..],
+        };
+        let tokens = rest.take(n_tokens * TOKEN_ENTRY_LEN)?;
+        let rank_table = rest.take(rank_table_len * 4)?;
+        let slots = rest.take(n_slots * TOKEN_ENTRY_LEN)?;
+        let mut specials = Cursor {
+            data: rest.take(n_special * 8)?,
+        };
+        let tags = rest.take(n_slots)?;
+        let token_bytes = rest.take(token_bytes_len)?;
+        let mut special_bytes = Cursor {
+            data: rest.take(special_len)?,
+        };
+        let pattern = std::str::from_utf8(rest.take(pattern_len)?)
+            .map_err(|_| error("pattern is not valid UTF-8"))?;
+        if !rest.data.is_empty() {
+            return Err(error("trailing data"));
+        }
+
+        // Check the tables are consistent, so that lookups stay in bounds and terminate
+        let mut last: Option<&[u8]> = None;
+        for (i, entry) in tokens.chunks_exact(TOKEN_ENTRY_LEN).enumerate() {
+            let offset = u32_at(entry, 0) as usize;
+            let len = u32_at(entry, 4) as usize;
+            let rank = u32_at(entry, 8) as usize;
+            let Some(token) = token_bytes.get(offset..offset + len) else {
+                return Err(error("token out of bounds"));
+            };
+            if last.is_some_and(|last| last >= token) {
+                return Err(error("tokens are not sorted"));
+            }
+            last = Some(token);
+            if rank >= rank_table_len || u32_at(rank_table, rank * 4) as usize != i {
+                return Err(error("rank table does not match tokens"));
+            }
+        }
+        let n_ranked = rank_table
+            .chunks_exact(4)
+            .filter(|index| u32_at(index, 0) != NO_TOKEN)
+            .count();
+        if n_ranked != n_tokens {
+            return Err(error("rank table does not match tokens"));
+        }
+        let mut n_indexed = 0;
+        for (entry, &tag) in slots.chunks_exact(TOKEN_ENTRY_LEN).zip(tags) {
+            if tag == 0 {
+                if entry.iter().any(|&b| b != 0) {
+                    return Err(error("token index does not match tokens"));
+                }
+                continue;
+            }
+            // An entry in the index must be a copy of the token entry with its rank
+            let rank = u32_at(entry, 8) as usize;
+            let index = match rank_table.get(rank * 4..rank * 4 + 4) {
+                Some(index) => u32_at(index, 0),
+                None => NO_TOKEN,
+            };
+            if tag < 0x80
+                || index == NO_TOKEN
+                || tokens[index as usize * TOKEN_ENTRY_LEN..][..TOKEN_ENTRY_LEN] != *entry
+            {
+                return Err(error("token index does not match tokens"));
+            }
+            n_indexed += 1;
+        }
+        if n_indexed != n_tokens {
+            return Err(error("token index does not match tokens"));
+        }
+
+        let mut special_tokens_encoder =
+            HashMap::with_capacity_and_hasher(n_special, Default::default());
+        for _ in 0..n_special {
+            let len = specials.len()?;
+            let rank = specials.u32()?;
+            let token = std::str::from_utf8(special_bytes.take(len)?)
+                .map_err(|_| error("special token is not valid UTF-8"))?;
+            special_tokens_encoder.insert(token.to_string(), rank);
+        }
+        let pattern = pattern.to_string();
+
+        let tokens_start = HEADER_LEN;
+        let rank_table_start = tokens_start + tokens.len();
+        let slots_start = rank_table_start + rank_table.len();
+        let tags_start = slots_start + slots.len() + n_special * 8;
+        let token_bytes_start = tags_start + tags.len();
+        let vocab = SnapshotVocab {
+            data: Arc::new(data),
+            n_tokens,
+            tokens: tokens_start,
+            rank_table: rank_table_start,
+            rank_table_len,
+            slots: slots_start,
+            tags: tags_start,
+            slot_mask: n_slots - 1,
+            slot_shift: 64 - n_slots.trailing_zeros(),
+            token_bytes: token_bytes_start,
+        };
+        Self::from_tables(Vocab::Snapshot(vocab), special_tokens_encoder, &pattern)
+            .map_err(|e| error(e.to_string()))
+    }
+
+    /// Loads a snapshot from disk. With the `mmap` feature, the file is memory mapped rather
+    /// than read, and stays mapped for as long as the `CoreBPE` (or a clone of it) is alive.
+    pub fn from_snapshot_file(
+        path: impl AsRef<Path>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let file = std::fs::File::open(path)?;
+        #[cfg(feature = "mmap")]
+        {
+            // SAFETY: snapshot files are written once and then only read. As with any memory map,
+            // modifying or truncating the file while it is mapped is a bug in the caller, and can
+            // change results or crash the process.
+            let data = unsafe { memmap2::Mmap::map(&file)? };
+            Ok(Self::from_snapshot_data(SnapshotData::Mmap(data))?)
+        }
+        #[cfg(not(feature = "mmap"))]
+        {
+            let mut data = Vec::new();
+            io::Read::read_to_end(&mut io::BufReader::new(file), &mut data)?;
+            Ok(Self::from_snapshot_data(SnapshotData::Bytes(data))?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AllowedSpecial;
+
+    fn setup_bpe() -> CoreBPE {
+        let mut encoder: HashMap<Vec<u8>, Rank> =
+            (0..=255u8).map(|b| (vec![b], b.into())).collect();
+        encoder.insert(b"ab".to_vec(), 256);
+        encoder.insert(b"cd".to_vec(), 257);
+        let special_tokens = HashMap::from_iter([("<|endoftext|>".to_string(), 258)]);
+        CoreBPE::new_internal(encoder, special_tokens, r"\w+|\s+|[^\w\s]+").unwrap()
+    }
+
+    fn setup_snapshot(bpe: &CoreBPE) -> Vec<u8> {
+        let mut data = Vec::new();
+        bpe.write_snapshot(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let bpe = setup_bpe();
+        let data = setup_snapshot(&bpe);
+        let loaded = CoreBPE::from_snapshot(&data).unwrap();
+        assert!(matches!(loaded.vocab, Vocab::Snapshot(_)));
+        assert_eq!(loaded.mergeable_ranks(), bpe.mergeable_ranks());
+        for (token, &rank) in bpe.mergeable_ranks().iter() {
+            assert_eq!(loaded.vocab.get(token), Some(rank));
+            assert_eq!(loaded.decode_single_token_bytes(rank).unwrap(), token);
+        }
+        assert_eq!(loaded.vocab.get(b"abc"), None);
+        assert_eq!(loaded.vocab.get(b""), None);
+        assert!(loaded.decode_single_token_bytes(1000).is_err());
+        assert!(loaded.vocab.sorted().eq(bpe.vocab.sorted()));
+        assert_eq!(loaded.special_tokens_encoder, bpe.special_tokens_encoder);
+        assert_eq!(loaded.special_tokens_decoder, bpe.special_tokens_decoder);
+        assert_eq!(loaded.pattern(), bpe.pattern());
+        assert_eq!(
+            loaded.encode_with_special_tokens("abcd <|endoftext|>"),
+            vec![256, 257, 32, 258]
+        );
+        assert_eq!(setup_snapshot(&loaded), data);
+    }
+
+    #[test]
+    fn test_snapshot_matches_owned_tables() {
+        let mut encoder: HashMap<Vec<u8>, Rank> =
+            (0..=255u8).map(|b| (vec![b], b.into())).collect();
+        let mut state: u32 = 0x9e3779b9;
+        let mut next = |n: u32| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state % n
+        };
+        // Every token is a merge of two existing tokens, so ranks follow merge priority
+        let mut tokens: Vec<Vec<u8>> = (0..=255u8)
+            .filter(|b| b" abcde".contains(b))
+            .map(|b| vec![b])
+            .collect();
+        while encoder.len() < 3000 {
+            let token = [
+                tokens[next(tokens.len() as u32) as usize].as_slice(),
+                tokens[next(tokens.len() as u32) as usize].as_slice(),
+            ]
+            .concat();
+            if !encoder.contains_key(&token) {
+                encoder.insert(token.clone(), encoder.len() as Rank);
+                tokens.push(token);
+            }
+        }
+        let bpe = CoreBPE::new_internal(encoder, HashMap::default(), r"\S+|\s+").unwrap();
+        let loaded = CoreBPE::from_snapshot(&setup_snapshot(&bpe)).unwrap();
+
+        let text: String = (0..5000)
+            .map(|_| " abcdef\n"[next(8) as usize..][..1].to_string())
+            .collect();
+        let tokens = bpe.encode_ordinary(&text);
+        assert_eq!(loaded.encode_ordinary(&text), tokens);
+        assert_eq!(loaded.decode_bytes(&tokens).unwrap(), text.as_bytes());
+        assert_eq!(
+            loaded._encode_unstable_native("abc ab", AllowedSpecial::None_),
+            bpe._encode_unstable_native("abc ab", AllowedSpecial::None_)
+        );
+    }
+
+    #[test]
+    fn test_snapshot_file() {
+        let bpe = setup_bpe();
+        let path =
+            std::env::temp_dir().join(format!("tiktoken-test-{}.snapshot", std::process::id()));
+        bpe.write_snapshot_file(&path).unwrap();
+        let loaded = CoreBPE::from_snapshot_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.encode_ordinary("abcd ab"), vec![256, 257, 32, 256]);
+    }
+
+    #[test]
+    fn test_snapshot_corrupted() {
+        let data = setup_snapshot(&setup_bpe());
+
+        let mut corrupted = data.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let err = CoreBPE::from_snapshot(&corrupted).err().unwrap();
+        assert_eq!(err.message, "checksum mismatch");
+
+        let mut corrupted = data.clone();
+        corrupted[8] = 3;
+        let err = CoreBPE::from_snapshot(&corrupted).err().unwrap();
+        assert_eq!(err.message, "unsupported version 3, expected 2");
+
+        let err = CoreBPE::from_snapshot(&data[..data.len() / 2])
+            .err()
+            .unwrap();
+        assert_eq!(err.message, "checksum mismatch");
+
+        // Inconsistent tables are caught even with a valid checksum
+        let mut corrupted = data.clone();
+        let rank_of_first_token = HEADER_LEN + 8;
+        corrupted[rank_of_first_token] ^= 1;
+        let checksum = hash(&corrupted[CHECKSUM_END..]);
+        corrupted[16..CHECKSUM_END].copy_from_slice(&checksum.to_le_bytes());
+        let err = CoreBPE::from_snapshot(&corrupted).err().unwrap();
+        assert_eq!(err.message, "rank table does not match tokens");
+
+        let mut corrupted = data.clone();
+        let n_tokens = u32_at(&data, CHECKSUM_END) as usize;
+        let rank_table_len = u32_at(&data, CHECKSUM_END + 20) as usize;
+        let slots = HEADER_LEN + n_tokens * TOKEN_ENTRY_LEN + rank_table_len * 4;
+        let len_of_first_indexed = (slots..)
+            .step_by(TOKEN_ENTRY_LEN)
+            .find(|&pos| u32_at(&data, pos + 4) != 0)
+            .unwrap()
+            + 4;
+        corrupted[len_of_first_indexed] += 1;
+        let checksum = hash(&corrupted[CHECKSUM_END..]);
+        corrupted[16..CHECKSUM_END].copy_from_slice(&checksum.to_le_bytes());
+        let err = CoreBPE::from_snapshot(&corrupted).err().unwrap();
+        assert_eq!(err.message, "token index does not match tokens");
+
+        assert!(CoreBPE::from_snapshot(b"not a snapshot").is_err());
+    }
+}
@@ -0,0 +1,605 @@
+//! Encoding text that arrives in chunks, and decoding tokens that arrive one at a time.
+
+use std::io::{self, Read};
+use std::iter::FusedIterator;
+
+use fancy_regex::{Match, Matches};
+
+use crate::{AllowedSpecial, CoreBPE, DecodeKeyError, EncodeError, Rank};
+
+/// Pieces that end within this many characters of the end of the buffered text are held back.
+/// When splitting, the regex may have looked this far past the start of a piece and hit the end
+/// of the text, e.g. to match a contraction like `'ll` after an apostrophe.
+const LOOKAHEAD_CHARS: usize = 3;
+
+const READ_CHUNK_SIZE: usize = 1 << 16;
+
+/// The most text, in bytes, that [`StreamingEncoder`] waits for before splitting buffered text
+/// again after a split that found nothing stable.
+const MAX_RESPLIT_DELAY: usize = 1 << 16;
+
+/// Encodes text pushed in chunks, emitting tokens once more text can no longer change them.
+///
+/// The concatenation of everything returned by the `push_*` methods and `finish` is identical to
+/// `encode_ordinary` on the concatenation of the chunks. Pieces near the end of the buffered text
+/// are unstable, and so are the tokens before them that `_increase_last_piece_token_len` finds,
+/// since whitespace can merge with what comes next (e.g. "\n" + " " + "\n" becoming "\n \n").
+/// Those pieces stay buffered until more text arrives.
+///
+/// Buffered text has to be split again from its start to find out whether it has become stable.
+/// So that text that stays unstable for a long time, like a very long word pushed a character at
+/// a time, is not split again on every push, a split that finds nothing stable waits for as much
+/// new text as was buffered, up to `MAX_RESPLIT_DELAY` bytes, before the next one. Tokens can
+/// therefore be emitted up to that much text after they become stable.
+pub struct StreamingEncoder<'a> {
+    bpe: &'a CoreBPE,
+    /// Text that has not been emitted as tokens yet. It starts at a stable piece boundary.
+    text: String,
+    /// A trailing incomplete UTF-8 sequence from `push_bytes`.
+    pending_bytes: Vec<u8>,
+    /// The length `text` has to reach before it is split again.
+    resplit_len: usize,
+}
+
+impl<'a> StreamingEncoder<'a> {
+    pub fn new(bpe: &'a CoreBPE) -> Self {
+        StreamingEncoder {
+            bpe,
+            text: String::new(),
+            pending_bytes: Vec::new(),
+            resplit_len: 0,
+        }
+    }
+
+    /// Adds text and returns the tokens that have become stable.
+    pub fn push_str(&mut self, text: &str) -> Result<Vec<Rank>, EncodeError> {
+        if !self.pending_bytes.is_empty() {
+            return Err(EncodeError {
+                message: "text pushed after an incomplete UTF-8 sequence".to_string(),
+            });
+        }
+        self.text.push_str(text);
+        self.encode_stable()
+    }
+
+    /// Adds UTF-8 bytes and returns the tokens that have become stable. Chunks may split a
+    /// character, in which case its first bytes are kept until the rest arrives.
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> Result<Vec<Rank>, EncodeError> {
+        self.pending_bytes.extend_from_slice(bytes);
+        let valid_up_to = match std::str::from_utf8(&self.pending_bytes) {
+            Ok(_) => self.pending_bytes.len(),
+            // An incomplete character at the end, which the next chunk may complete
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => {
+                return Err(EncodeError {
+                    message: format!("invalid UTF-8: {e}"),
+                });
+            }
+        };
+        let valid = std::str::from_utf8(&self.pending_bytes[..valid_up_to]).unwrap();
+        self.text.push_str(valid);
+        self.pending_bytes.drain(..valid_up_to);
+        self.encode_stable()
+    }
+
+    /// Encodes the remaining text.
+    pub fn finish(self) -> Result<Vec<Rank>, EncodeError> {
+        if !self.pending_bytes.is_empty() {
+            return Err(EncodeError {
+                message: "input ends with an incomplete UTF-8 sequence".to_string(),
+            });
+        }
+        let mut tokens = vec![];
+        for (start, end) in self.pieces()? {
+            self.bpe
+                .encode_piece(&self.text.as_bytes()[start..end], &mut tokens);
+        }
+        Ok(tokens)
+    }
+
+    fn pieces(&self) -> Result<Vec<(usize, usize)>, EncodeError> {
+        self.bpe
+            ._get_tl_regex()
+            .find_iter(&self.text)
+            .map(|mat| {
+                mat.map(|m| (m.start(), m.end())).map_err(|e| EncodeError {
+                    message: format!("Regex error while tokenizing: {e}"),
+                })
+            })
+            .collect()
+    }
+
+    fn encode_stable(&mut self) -> Result<Vec<Rank>, EncodeError> {
+        if self.text.len() < self.resplit_len {
+            return Ok(vec![]);
+        }
+        let tokens = self.split_stable()?;
+        self.resplit_len = if tokens.is_empty() {
+            self.text.len() + self.text.len().min(MAX_RESPLIT_DELAY)
+        } else {
+            0
+        };
+        Ok(tokens)
+    }
+
+    /// Encodes and removes the stable pieces at the start of `text`.
+    fn split_stable(&mut self) -> Result<Vec<Rank>, EncodeError> {
+        let pieces = self.pieces()?;
+        // Pieces that end within LOOKAHEAD_CHARS of the end may still change, so the first of
+        // them plays the part of the last piece in `_encode_unstable_native`
+        let n_complete = pieces.partition_point(|&(_, end)| {
+            self.text[end..].chars().nth(LOOKAHEAD_CHARS - 1).is_some()
+        });
+        if n_complete == 0 {
+            return Ok(vec![]);
+        }
+        let mut tokens = vec![];
+        let mut piece_token_ends = Vec::with_capacity(n_complete);
+        for &(start, end) in &pieces[..n_complete] {
+            self.bpe
+                .encode_piece(&self.text.as_bytes()[start..end], &mut tokens);
+            piece_token_ends.push(tokens.len());
+        }
+        let last_piece_token_len = match pieces.get(n_complete) {
+            Some(&(start, end)) => self
+                .bpe
+                .encode_piece(&self.text.as_bytes()[start..end], &mut tokens),
+            None => 0,
+        };
+        let (mut tokens, unstable_len) = self
+            .bpe
+            ._increase_last_piece_token_len(tokens, last_piece_token_len);
+
+        // The text after the emitted tokens is split again, so they have to end on a piece
+        // boundary
+        let stable_len = tokens.len() - unstable_len;
+        let n_stable = piece_token_ends.partition_point(|&end| end <= stable_len);
+        if n_stable == 0 {
+            return Ok(vec![]);
+        }
+        tokens.truncate(piece_token_ends[n_stable - 1]);
+        self.text.drain(..pieces[n_stable - 1].1);
+        Ok(tokens)
+    }
+}
+
+/// Bytes that may end in an incomplete UTF-8 sequence.
+#[derive(Debug, Default)]
+pub(crate) struct Utf8Buffer {
+    pending: Vec<u8>,
+}
+
+impl Utf8Buffer {
+    /// Appends `bytes`, and moves everything up to a trailing incomplete sequence to `out`.
+    /// Sequences that can never become valid are replaced with U+FFFD, as in
+    /// `String::from_utf8_lossy`.
+    pub(crate) fn push(&mut self, bytes: &[u8], out: &mut String) {
+        self.pending.extend_from_slice(bytes);
+        let mut rest = self.pending.as_slice();
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    out.push_str(valid);
+                    rest = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, after) = rest.split_at(e.valid_up_to());
+                    out.push_str(std::str::from_utf8(valid).unwrap());
+                    match e.error_len() {
+                        Some(len) => {
+                            out.push(char::REPLACEMENT_CHARACTER);
+                            rest = &after[len..];
+                        }
+                        None => {
+                            rest = after;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        let consumed = self.pending.len() - rest.len();
+        self.pending.drain(..consumed);
+    }
+
+    /// Moves the remaining bytes to `out`, replacing an incomplete sequence with U+FFFD.
+    pub(crate) fn finish(&mut self, out: &mut String) {
+        out.push_str(&String::from_utf8_lossy(&self.pending));
+        self.pending.clear();
+    }
+}
+
+/// Decodes tokens one at a time into complete UTF-8 text.
+///
+/// A token can end partway through a character, in which case its trailing bytes are held back
+/// until the tokens completing the character arrive. The concatenation of everything returned
+/// by `push` and `finish` equals `decode` with [`DecodeMode::Replace`](crate::DecodeMode).
+pub struct StreamingDecoder<'a> {
+    bpe: &'a CoreBPE,
+    buffer: Utf8Buffer,
+    out: String,
+}
+
+impl<'a> StreamingDecoder<'a> {
+    pub fn new(bpe: &'a CoreBPE) -> Self {
+        StreamingDecoder {
+            bpe,
+            buffer: Utf8Buffer::default(),
+            out: String::new(),
+        }
+    }
+
+    /// Adds a token and returns the text it completes, which may be empty.
+    pub fn push(&mut self, token: Rank) -> Result<&str, DecodeKeyError> {
+        self.out.clear();
+        let bytes = self.bpe.decode_single_token_bytes(token)?;
+        self.buffer.push(bytes, &mut self.out);
+        Ok(&self.out)
+    }
+
+    /// Returns any held back bytes, with an incomplete character replaced by U+FFFD.
+    pub fn finish(mut self) -> String {
+        self.out.clear();
+        self.buffer.finish(&mut self.out);
+        self.out
+    }
+}
+
+/// Lazily encodes text like `encode`, from [`CoreBPE::encode_iter`].
+///
+/// The text is split into pieces only as tokens are needed, so dropping the iterator early skips
+/// encoding the rest of the text. An error ends the iteration.
+pub struct EncodeIter<'a> {
+    bpe: &'a CoreBPE,
+    text: &'a str,
+    allowed_special: AllowedSpecial<'a>,
+    /// Regex pieces of the text up to `special`.
+    pieces: Matches<'a, 'a, str>,
+    /// The allowed special token after the current pieces, if any.
+    special: Option<Match<'a>>,
+    /// Tokens of the current piece, of which the first `next_token` have been yielded.
+    tokens: Vec<Rank>,
+    next_token: usize,
+    done: bool,
+}
+
+impl<'a> EncodeIter<'a> {
+    fn new(bpe: &'a CoreBPE, text: &'a str, allowed_special: AllowedSpecial<'a>) -> Self {
+        let (pieces, special) = Self::segment(bpe, text, 0, allowed_special);
+        EncodeIter {
+            bpe,
+            text,
+            allowed_special,
+            pieces,
+            special,
+            tokens: vec![],
+            next_token: 0,
+            done: false,
+        }
+    }
+
+    /// Returns the pieces from `start` up to the next allowed special token, and that token.
+    fn segment(
+        bpe: &'a CoreBPE,
+        text: &'a str,
+        start: usize,
+        allowed_special: AllowedSpecial,
+    ) -> (Matches<'a, 'a, str>, Option<Match<'a>>) {
+        let special = bpe.find_allowed_special(text, start, allowed_special);
+        let end = special.map_or(text.len(), |m| m.start());
+        (bpe._get_tl_regex().find_iter(&text[start..end]), special)
+    }
+}
+
+impl Iterator for EncodeIter<'_> {
+    type Item = Result<Rank, EncodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(&token) = self.tokens.get(self.next_token) {
+                self.next_token += 1;
+                return Some(Ok(token));
+            }
+            if self.done {
+                return None;
+            }
+            match self.pieces.next() {
+                Some(Ok(m)) => {
+                    self.tokens.clear();
+                    self.next_token = 0;
+                    self.bpe
+                        .encode_piece(m.as_str().as_bytes(), &mut self.tokens);
+                }
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(EncodeError {
+                        message: format!("Regex error while tokenizing: {e}"),
+                    }));
+                }
+                None => {
+                    let Some(special) = self.special else {
+                        self.done = true;
+                        return None;
+                    };
+                    (self.pieces, self.special) =
+                        Self::segment(self.bpe, self.text, special.end(), self.allowed_special);
+                    return Some(Ok(self.bpe.special_tokens_encoder[special.as_str()]));
+                }
+            }
+        }
+    }
+}
+
+impl FusedIterator for EncodeIter<'_> {}
+
+impl CoreBPE {
+    pub fn streaming_encoder(&self) -> StreamingEncoder<'_> {
+        StreamingEncoder::new(self)
+    }
+
+    pub fn streaming_decoder(&self) -> StreamingDecoder<'_> {
+        StreamingDecoder::new(self)
+    }
+
+    /// Encodes `text` like `encode`, yielding tokens as they are found. Stopping early, e.g.
+    /// with `take` or `find`, skips encoding the rest of the text. If `allowed_special` allows
+    /// no special tokens, the rest of the text is not read at all. Otherwise it is still searched
+    /// as far as the next allowed special token, which is cheap next to encoding it.
+    pub fn encode_iter<'a>(
+        &'a self,
+        text: &'a str,
+        allowed_special: impl Into<AllowedSpecial<'a>>,
+    ) -> EncodeIter<'a> {
+        EncodeIter::new(self, text, allowed_special.into())
+    }
+
+    /// Encodes UTF-8 text from a reader without special tokens, like `encode_ordinary`, passing
+    /// tokens to `on_tokens` as they become stable.
+    pub fn encode_ordinary_reader<R: Read>(
+        &self,
+        mut reader: R,
+        mut on_tokens: impl FnMut(&[Rank]),
+    ) -> io::Result<()> {
+        let to_io_error = |e: EncodeError| io::Error::new(io::ErrorKind::InvalidData, e);
+        let mut encoder = self.streaming_encoder();
+        let mut buf = vec![0; READ_CHUNK_SIZE];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            on_tokens(&encoder.push_bytes(&buf[..n]).map_err(to_io_error)?);
+        }
+        on_tokens(&encoder.finish().map_err(to_io_error)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::time::{Duration, Instant};
+
+    use rustc_hash::FxHashMap as HashMap;
+
+    use super::*;
+    use crate::DecodeMode;
+    use crate::openai_public::{CL100K_PAT_STR, O200K_PAT_STR, R50K_PAT_STR};
+
+    fn setup_bpe(pattern: &str) -> CoreBPE {
+        let mut encoder: HashMap<Vec<u8>, Rank> =
+            (0..=255u8).map(|b| (vec![b], b.into())).collect();
+        for token in ["in", " t", "th", " th", "  ", "\n\n", "'s", "ll"] {
+            let rank = encoder.len() as Rank;
+            encoder.insert(token.as_bytes().to_vec(), rank);
+        }
+        CoreBPE::new_internal(encoder, HashMap::default(), pattern).unwrap()
+    }
+
+    /// A tiny xorshift generator, so the test is deterministic without extra dependencies.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    fn random_text(rng: &mut Rng) -> String {
+        const PARTS: &[&str] = &[
+            "a", "th", "in", "'", "'s", "'ll", "l", " ", "  ", "\n", "\r\n", "\t", "!", "/", "1",
+            "234", "é", "世界", "😀", "\u{a0}", "A",
+        ];
+        (0..rng.next(60))
+            .map(|_| PARTS[rng.next(PARTS.len())])
+            .collect()
+    }
+
+    #[test]
+    fn test_streaming_encoder_matches_encode_ordinary() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        for pattern in [R50K_PAT_STR, CL100K_PAT_STR, O200K_PAT_STR] {
+            let bpe = setup_bpe(pattern);
+            for _ in 0..500 {
+                let text = random_text(&mut rng);
+                let expected = bpe.encode_ordinary(&text);
+
+                // Split into random byte chunks, which may split characters
+                let bytes = text.as_bytes();
+                let mut encoder = bpe.streaming_encoder();
+                let mut tokens = vec![];
+                let mut pos = 0;
+                while pos < bytes.len() {
+                    let end = (pos + 1 + rng.next(8)).min(bytes.len());
+                    tokens.extend(encoder.push_bytes(&bytes[pos..end]).unwrap());
+                    pos = end;
+                }
+                tokens.extend(encoder.finish().unwrap());
+                assert_eq!(tokens, expected, "{text:?} with {pattern}");
+
+                // One character at a time
+                let mut encoder = bpe.streaming_encoder();
+                let mut tokens = vec![];
+                for c in text.chars() {
+                    tokens.extend(encoder.push_str(c.encode_utf8(&mut [0; 4])).unwrap());
+                }
+                tokens.extend(encoder.finish().unwrap());
+                assert_eq!(tokens, expected, "{text:?} with {pattern}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_streaming_encoder_emits_early() {
+        let bpe = setup_bpe(CL100K_PAT_STR);
+        let mut encoder = bpe.streaming_encoder();
+        assert!(encoder.push_str("in").unwrap().is_empty());
+        assert_eq!(
+            encoder.push_str(" the cat").unwrap(),
+            bpe.encode_ordinary("in the")
+        );
+        assert_eq!(encoder.text, " cat");
+
+        // A piece that stays unstable is not split again on every push. Splitting this word
+        // again each time takes tens of seconds in a debug build.
+        let word = "th".repeat(1 << 15);
+        let start = Instant::now();
+        let mut encoder = bpe.streaming_encoder();
+        let mut tokens = vec![];
+        for c in word.chars().chain(" in".chars()) {
+            tokens.extend(encoder.push_str(c.encode_utf8(&mut [0; 4])).unwrap());
+        }
+        tokens.extend(encoder.finish().unwrap());
+        assert_eq!(tokens, bpe.encode_ordinary(&(word + " in")));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // However long the unstable text, tokens are held back for at most
+        // MAX_RESPLIT_DELAY bytes of new text
+        let word = "th".repeat(1 << 17);
+        let mut encoder = bpe.streaming_encoder();
+        let mut tokens = encoder.push_str(&word).unwrap();
+        assert!(tokens.is_empty());
+        let chunk = " in".repeat(1 << 10);
+        let mut text = word;
+        while tokens.is_empty() {
+            tokens = encoder.push_str(&chunk).unwrap();
+            text += &chunk;
+        }
+        assert!(text.len() - 2 * (1 << 17) <= MAX_RESPLIT_DELAY + chunk.len());
+        tokens.extend(encoder.finish().unwrap());
+        assert_eq!(tokens, bpe.encode_ordinary(&text));
+    }
+
+    #[test]
+    fn test_encode_ordinary_reader() {
+        let bpe = setup_bpe(O200K_PAT_STR);
+        let text = "it'll be fine\n\n世界 ".repeat(10_000);
+        let mut tokens = vec![];
+        bpe.encode_ordinary_reader(text.as_bytes(), |t| tokens.extend_from_slice(t))
+            .unwrap();
+        assert_eq!(tokens, bpe.encode_ordinary(&text));
+
+        let err = bpe
+            .encode_ordinary_reader(b"ab\xff".as_slice(), |_| {})
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = bpe
+            .encode_ordinary_reader(b"ab\xe4\xb8".as_slice(), |_| {})
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_encode_iter() {
+        let mut bpe = setup_bpe(CL100K_PAT_STR);
+        let special_tokens = HashMap::from_iter([
+            ("<|endoftext|>".to_string(), 1000),
+            ("<|fim|>".to_string(), 1001),
+        ]);
+        bpe = CoreBPE::new_internal(
+            bpe.mergeable_ranks().into_owned(),
+            special_tokens,
+            CL100K_PAT_STR,
+        )
+        .unwrap();
+        let only_fim = HashSet::from(["<|fim|>"]);
+        let mut rng = Rng(0xda942042e4dd58b5);
+        for _ in 0..300 {
+            let mut text = random_text(&mut rng);
+            for _ in 0..rng.next(3) {
+                let at = text.floor_char_boundary(rng.next(text.len() + 1));
+                let special = ["<|endoftext|>", "<|fim|>", "<|fim"][rng.next(3)];
+                text.insert_str(at, special);
+            }
+            for allowed_special in [
+                AllowedSpecial::None_,
+                AllowedSpecial::All,
+                AllowedSpecial::Only(&only_fim),
+            ] {
+                let tokens: Result<Vec<Rank>, _> =
+                    bpe.encode_iter(&text, allowed_special).collect();
+                let (expected, _) = bpe.encode(&text, allowed_special).unwrap();
+                assert_eq!(tokens.unwrap(), expected, "{text:?}");
+            }
+        }
+
+        let text = "in the <|endoftext|> th";
+        let mut iter = bpe.encode_iter(text, AllowedSpecial::All);
+        let (expected, _) = bpe.encode(text, AllowedSpecial::All).unwrap();
+        let position = iter.position(|token| token.unwrap() == 1000);
+        assert_eq!(position, expected.iter().position(|&token| token == 1000));
+        assert_eq!(iter.count(), 1);
+        assert!(bpe.encode_iter("", AllowedSpecial::All).next().is_none());
+
+        // Searching this tail for special tokens takes the best part of a second in a debug
+        // build, so taking a few tokens must not search it
+        let text = format!("hello{}", "<|endoftex ".repeat(1 << 20));
+        for allowed_special in [AllowedSpecial::None_, AllowedSpecial::Only(&HashSet::new())] {
+            let start = Instant::now();
+            assert_eq!(bpe.encode_iter(&text, allowed_special).take(3).count(), 3);
+            assert!(start.elapsed() < Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn test_streaming_decoder() {
+        let bpe = setup_bpe(CL100K_PAT_STR);
+        let mut decoder = bpe.streaming_decoder();
+        // "é" is 0xc3 0xa9 and "😀" is 0xf0 0x9f 0x98 0x80
+        assert_eq!(decoder.push(256).unwrap(), "in");
+        assert_eq!(decoder.push(0xc3).unwrap(), "");
+        assert_eq!(decoder.push(0xa9).unwrap(), "é");
+        assert_eq!(decoder.push(0xf0).unwrap(), "");
+        assert_eq!(decoder.push(0x9f).unwrap(), "");
+        // An invalid continuation, so the incomplete character can be replaced right away
+        assert_eq!(decoder.push(b'a'.into()).unwrap(), "\u{fffd}a");
+        assert_eq!(decoder.push(0xff).unwrap(), "\u{fffd}");
+        assert_eq!(decoder.push(0xe4).unwrap(), "");
+        assert_eq!(decoder.push(999).unwrap_err().token, 999);
+        assert_eq!(decoder.finish(), "\u{fffd}");
+    }
+
+    #[test]
+    fn test_streaming_decoder_matches_decode() {
+        let bpe = setup_bpe(CL100K_PAT_STR);
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        for _ in 0..500 {
+            let mut tokens = bpe.encode_ordinary(&random_text(&mut rng));
+            // Also decode arbitrary bytes, which need not be valid UTF-8
+            tokens.extend((0..rng.next(4)).map(|_| rng.next(256) as Rank));
+            let mut decoder = bpe.streaming_decoder();
+            let mut text = String::new();
+            for &token in &tokens {
+                text.push_str(decoder.push(token).unwrap());
+            }
+            text.push_str(&decoder.finish());
+            assert_eq!(text, bpe.decode(&tokens, DecodeMode::Replace).unwrap());
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! The mergeable token tables of a `CoreBPE`.
+//!
+//! A `CoreBPE` built from a rank map owns hash maps, like it always has. One loaded from a
+//! [snapshot](crate::snapshot) reads the tables stored in the snapshot in place instead, so that
+//! loading does no per-token work beyond validation.
+
+use std::borrow::Cow;
+use std::ops::Index;
+
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::snapshot::SnapshotVocab;
+use crate::{_byte_pair_count, _byte_pair_encode, _byte_pair_merge_large, MergeScratch, Rank};
+
+/// Looks up the rank of a mergeable token, for the BPE merge functions.
+pub(crate) trait Ranks {
+    fn rank(&self, token: &[u8]) -> Option<Rank>;
+}
+
+impl Ranks for HashMap<Vec<u8>, Rank> {
+    #[inline]
+    fn rank(&self, token: &[u8]) -> Option<Rank> {
+        self.get(token).copied()
+    }
+}
+
+impl Ranks for SnapshotVocab {
+    #[inline]
+    fn rank(&self, token: &[u8]) -> Option<Rank> {
+        self.get(token)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) enum Vocab {
+    Owned {
+        encoder: HashMap<Vec<u8>, Rank>,
+        decoder: HashMap<Rank, Vec<u8>>,
+        sorted_token_bytes: Vec<Vec<u8>>,
+    },
+    Snapshot(SnapshotVocab),
+}
+
+impl Vocab {
+    /// Builds the tables from a rank map.
+    pub(crate) fn new(encoder: HashMap<Vec<u8>, Rank>) -> Self {
+        let decoder: HashMap<Rank, Vec<u8>> =
+            encoder.iter().map(|(k, v)| (*v, k.clone())).collect();
+
+        assert!(
+            encoder.len() == decoder.len(),
+            "Encoder and decoder must be of equal length. Encoder length: {}, decoder length: {}.\nMaybe you had duplicate token indices in your encoder?",
+            encoder.len(),
+            decoder.len()
+        );
+
+        // Clone because I don't know how to tell Rust I'm not going to change the map
+        let mut sorted_token_bytes: Vec<Vec<u8>> = encoder.keys().cloned().collect();
+        sorted_token_bytes.sort();
+
+        Vocab::Owned {
+            encoder,
+            decoder,
+            sorted_token_bytes,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn get(&self, token: &[u8]) -> Option<Rank> {
+        match self {
+            Vocab::Owned { encoder, .. } => encoder.get(token).copied(),
+            Vocab::Snapshot(vocab) => vocab.get(token),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn contains(&self, token: &[u8]) -> bool {
+        self.get(token).is_some()
+    }
+
+    /// The bytes of the token with rank `rank`.
+    #[inline]
+    pub(crate) fn token(&self, rank: Rank) -> Option<&[u8]> {
+        match self {
+            Vocab::Owned { decoder, .. } => decoder.get(&rank).map(Vec::as_slice),
+            Vocab::Snapshot(vocab) => vocab.token(rank),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Vocab::Owned { encoder, .. } => encoder.len(),
+            Vocab::Snapshot(vocab) => vocab.len(),
+        }
+    }
+
+    /// The `i`th token in order of its bytes.
+    fn sorted_token(&self, i: usize) -> &[u8] {
+        match self {
+            Vocab::Owned {
+                sorted_token_bytes, ..
+            } => &sorted_token_bytes[i],
+            Vocab::Snapshot(vocab) => vocab.sorted_token(i),
+        }
+    }
+
+    /// All tokens and their ranks, in order of their bytes.
+    pub(crate) fn sorted(&self) -> impl Iterator<Item = (&[u8], Rank)> {
+        (0..self.len()).map(|i| {
+            let token = self.sorted_token(i);
+            (token, self.get(token).unwrap())
+        })
+    }
+
+    /// The tokens that start with `prefix`, in order of their bytes.
+    pub(crate) fn with_prefix<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = &'a [u8]> {
+        // `partition_point` over the sorted tokens
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.sorted_token(mid) < prefix {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        (lo..self.len())
+            .map(|i| self.sorted_token(i))
+            .take_while(move |token| token.starts_with(prefix))
+    }
+
+    /// The tokens as a rank map, which is only built if the tables are not already one.
+    pub(crate) fn to_ranks(&self) -> Cow<'_, HashMap<Vec<u8>, Rank>> {
+        match self {
+            Vocab::Owned { encoder, .. } => Cow::Borrowed(encoder),
+            Vocab::Snapshot(_) => Cow::Owned(
+                self.sorted()
+                    .map(|(token, rank)| (token.to_vec(), rank))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+// The BPE merge functions do many lookups per piece, so these match on the kind of table once
+// and run a copy of the merge loop specialised for it.
+impl Vocab {
+    pub(crate) fn byte_pair_encode(&self, piece: &[u8]) -> Vec<Rank> {
+        match self {
+            Vocab::Owned { encoder, .. } => _byte_pair_encode(piece, encoder),
+            Vocab::Snapshot(vocab) => _byte_pair_encode(piece, vocab),
+        }
+    }
+
+    /// `byte_pair_encode(piece).len()`, for pieces that are not a single token, reusing the
+    /// buffers in `scratch`.
+    pub(crate) fn byte_pair_count(&self, piece: &[u8], scratch: &mut MergeScratch) -> usize {
+        match self {
+            Vocab::Owned { encoder, .. } => _byte_pair_count(encoder, piece, scratch),
+            Vocab::Snapshot(vocab) => _byte_pair_count(vocab, piece, scratch),
+        }
+    }
+
+    /// BPE over the whole of `piece`, however long it is.
+    pub(crate) fn byte_pair_merge_large(&self, piece: &[u8]) -> Vec<Rank> {
+        match self {
+            Vocab::Owned { encoder, .. } => _byte_pair_merge_large(encoder, piece),
+            Vocab::Snapshot(vocab) => _byte_pair_merge_large(vocab, piece),
+        }
+    }
+}
+
+impl Index<Rank> for Vocab {
+    type Output = [u8];
+
+    fn index(&self, rank: Rank) -> &[u8] {
+        self.token(rank).expect("no token with this rank")
+    }
+}
@@ -1,5 +1,6 @@
 # Note that there are more actual tests, they're just not currently public :-)
 
+import functools
 from typing import Callable
 
 import hypothesis
@@ -167,6 +168,33 @@ def test_single_token_roundtrip(make_enc: Callable[[], tiktoken.Encoding]):
         assert enc.encode_single_token(token_bytes) == token
 
 
+@pytest.mark.parametrize("make_enc", ENCODING_FACTORIES)
+@hypothesis.given(text=st.text())
+@hypothesis.settings(deadline=None, max_examples=MAX_EXAMPLES)
+def test_hyp_count_tokens(make_enc: Callable[[], tiktoken.Encoding], text):
+    enc = make_enc()
+
+    n_tokens = len(enc.encode(text, allowed_special="all"))
+    assert enc.count_tokens(text, allowed_special="all") == n_tokens
+    n_tokens = len(enc.encode_ordinary(text))
+    assert enc.count_tokens_up_to(text, n_tokens) == n_tokens
+    if n_tokens > 0:
+        assert enc.count_tokens_up_to(text, n_tokens - 1) is None
+
+
+@pytest.mark.parametrize("make_enc", ENCODING_FACTORIES)
+@hypothesis.given(text=st.text())
+@hypothesis.settings(deadline=None, max_examples=MAX_EXAMPLES)
+def test_hyp_streaming_decoder(make_enc: Callable[[], tiktoken.Encoding], text):
+    enc = make_enc()
+
+    tokens = enc.encode_ordinary(text)
+    decoder = enc.streaming_decoder()
+    assert "".join(decoder.push(token) for token in tokens) + decoder.finish() == enc.decode(
+        tokens
+    )
+
+
 # ====================
 # Special tokens
 # ====================
@@ -223,6 +251,35 @@ def test_special_token():
     assert fim in tokens
 
 
+def test_disallowed_special_all_methods():
+    enc = tiktoken.get_encoding("cl100k_base")
+
+    text = "hello <|endoftext|>"
+    for encode in [
+        enc.encode,
+        enc.encode_with_offsets,
+        functools.partial(enc.encode_truncated, max_tokens=1),
+        enc.count_tokens,
+        lambda text, **kwargs: enc.encode_batch([text], **kwargs),
+        enc.encode_parallel,
+        enc.encode_with_unstable,
+    ]:
+        with pytest.raises(ValueError, match="endoftext"):
+            encode(text)
+        with pytest.raises(ValueError, match="endoftext"):
+            encode(text, allowed_special="all", disallowed_special={"<|endoftext|>"})
+        # Disallowed strings need not be special tokens
+        with pytest.raises(ValueError, match="'hello'"):
+            encode(text, disallowed_special={"hello"})
+        encode(text, allowed_special="all")
+        encode(text, disallowed_special=())
+        # A bare string other than "all" is not a collection of special tokens
+        with pytest.raises(TypeError):
+            encode(text, allowed_special="<|endoftext|>")
+        with pytest.raises(TypeError):
+            encode(text, disallowed_special="<|endoftext|>")
+
+
 @pytest.mark.parametrize("make_enc", ENCODING_FACTORIES)
 @hypothesis.given(text=st.text())
 @hypothesis.settings(deadline=None, max_examples=MAX_EXAMPLES)
@@ -251,6 +308,24 @@ def test_batch_encode(make_enc: Callable[[], tiktoken.Encoding]):
         enc.encode_ordinary(text2),
     ]
 
+    assert enc.encode_batch([text1, "\ud800"], num_threads=1) == [
+        enc.encode(text1),
+        enc.encode("\ud800"),
+    ]
+    with pytest.raises(ValueError):
+        enc.encode_batch([text1, "<|endoftext|>"])
+
+
+@pytest.mark.parametrize("make_enc", ENCODING_FACTORIES)
+def test_encode_parallel(make_enc: Callable[[], tiktoken.Encoding]):
+    enc = make_enc()
+    text = "".join(
+        f"Line {i}: hello world!\n\n  indented/path\r\nx = {i * 7919}\n" for i in range(20000)
+    )
+
+    assert enc.encode_parallel(text, num_threads=4) == enc.encode(text)
+    assert enc.encode_parallel("hello world") == enc.encode("hello world")
+
 
 @pytest.mark.parametrize("make_enc", ENCODING_FACTORIES)
 @hypothesis.given(batch=st.lists(st.text()))
@@ -77,3 +77,106 @@ def test_basic_offsets():
     p, o = enc.decode_with_offsets(enc.encode(prompt))
     assert p == prompt
     assert o == [0, 1]
+
+
+@pytest.mark.parametrize("make_enc", SOME_ENCODING_FACTORIES)
+@hypothesis.given(text=st.text())
+@hypothesis.settings(deadline=None, max_examples=MAX_EXAMPLES)
+def test_hyp_encode_with_offsets(make_enc: Callable[[], tiktoken.Encoding], text):
+    enc = make_enc()
+    text_bytes = text.encode("utf-8", "surrogatepass")
+    hypothesis.assume(text_bytes.decode("utf-8", "ignore") == text)
+
+    with_offsets = enc.encode_with_offsets(text, disallowed_special=())
+    assert [token for token, _, _ in with_offsets] == enc.encode(text, disallowed_special=())
+    pos = 0
+    for token, start, end in with_offsets:
+        assert start == pos
+        assert text_bytes[start:end] == enc.decode_single_token_bytes(token)
+        pos = end
+    assert pos == len(text_bytes)
+
+
+def test_basic_encode_with_offsets():
+    enc = tiktoken.get_encoding("cl100k_base")
+
+    prompt = "hello world<|endoftext|> green cow"
+    with_offsets = enc.encode_with_offsets(prompt, allowed_special="all")
+    assert [token for token, _, _ in with_offsets] == enc.encode(prompt, allowed_special="all")
+    assert [(start, end) for _, start, end in with_offsets] == [
+        (0, 5),
+        (5, 11),
+        (11, 24),
+        (24, 30),
+        (30, 34),
+    ]
+    with pytest.raises(ValueError):
+        enc.encode_with_offsets(prompt)
+
+
+def test_encode_with_offsets_units():
+    enc = tiktoken.get_encoding("cl100k_base")
+
+    # The first character is split across tokens, and the emoji is two UTF-16 code units
+    prompt = "我非常渴望 😀"
+    tokens = enc.encode(prompt)
+    for unit in ["chars", "utf16"]:
+        with_offsets = enc.encode_with_offsets(prompt, unit=unit)
+        assert [token for token, _, _ in with_offsets] == tokens
+        assert with_offsets[0][1] == 0
+        assert with_offsets[-1][2] == (len(prompt) if unit == "chars" else len(prompt) + 1)
+    # Each span covers every character the token has bytes of
+    for token, start, end in enc.encode_with_offsets(prompt, unit="chars"):
+        assert enc.decode_single_token_bytes(token) in prompt[start:end].encode("utf-8")
+
+
+@pytest.mark.parametrize("make_enc", SOME_ENCODING_FACTORIES)
+@hypothesis.given(text=st.text(), max_tokens=st.integers(min_value=1, max_value=20))
+@hypothesis.settings(deadline=None, max_examples=MAX_EXAMPLES)
+def test_hyp_encode_truncated(make_enc: Callable[[], tiktoken.Encoding], text, max_tokens):
+    enc = make_enc()
+
+    for keep in ["head", "tail", "middle"]:
+        tokens, head_end, tail_start = enc.encode_truncated(
+            text, max_tokens, keep=keep, ellipsis="..."
+        )
+        assert len(tokens) <= max_tokens
+        assert head_end <= tail_start <= len(text)
+        kept = text[:head_end] + text[tail_start:]
+        if keep == "middle" and head_end < tail_start:
+            kept = text[:head_end] + "..." + text[tail_start:]
+        assert enc.decode(tokens) == kept
+        if head_end == tail_start:
+            assert tokens == enc.encode(text)
+
+
+@pytest.mark.parametrize("make_enc", SOME_ENCODING_FACTORIES)
+@hypothesis.given(
+    text=st.text(),
+    max_tokens=st.integers(min_value=1, max_value=20),
+    overlap=st.integers(min_value=0, max_value=19),
+)
+@hypothesis.settings(deadline=None, max_examples=MAX_EXAMPLES)
+def test_hyp_chunk(make_enc: Callable[[], tiktoken.Encoding], text, max_tokens, overlap):
+    enc = make_enc()
+    overlap = min(overlap, max_tokens - 1)
+
+    chunks = enc.chunk(text, max_tokens, overlap=overlap)
+    if not text:
+        assert chunks == []
+        return
+    assert chunks[0][1] == 0
+    assert chunks[-1][2] == len(text)
+    for tokens, start, end in chunks:
+        assert tokens == enc.encode_ordinary(text[start:end])
+        assert len(tokens) <= max_tokens or end - start == 1
+    for (_, start, end), (_, next_start, _) in zip(chunks, chunks[1:]):
+        assert start < next_start <= end
+
+
+def test_chunk_prefers_paragraphs():
+    enc = tiktoken.get_encoding("cl100k_base")
+    paragraph = "This is a sentence. " * 10
+    text = (paragraph.strip() + "\n\n") * 3
+    chunks = enc.chunk(text, 60)
+    assert [text[start:end] for _, start, end in chunks] == [paragraph.strip() + "\n\n"] * 3
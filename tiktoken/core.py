@@ -1,14 +1,11 @@
 from __future__ import annotations
 
 import functools
-from concurrent.futures import ThreadPoolExecutor
-from typing import TYPE_CHECKING, AbstractSet, Collection, Literal, NoReturn, Sequence
+from typing import TYPE_CHECKING, AbstractSet, Collection, Literal, Sequence
 
 from tiktoken import _tiktoken
 
 if TYPE_CHECKING:
-    import re
-
     import numpy as np
     import numpy.typing as npt
 
@@ -113,18 +110,8 @@ class Encoding:
         [27, 91, 437, 1659, 5239, 91, 29]
         ```
         """
-        if allowed_special == "all":
-            allowed_special = self.special_tokens_set
-        if disallowed_special == "all":
-            disallowed_special = self.special_tokens_set - allowed_special
-        if disallowed_special:
-            if not isinstance(disallowed_special, frozenset):
-                disallowed_special = frozenset(disallowed_special)
-            if match := _special_token_regex(disallowed_special).search(text):
-                raise_disallowed_special_token(match.group())
-
         try:
-            return self._core_bpe.encode(text, allowed_special)
+            return self._core_bpe.encode_checked(text, allowed_special, disallowed_special)
         except UnicodeEncodeError:
             # BPE operates on bytes, but the regex operates on unicode. If we pass a str that is
             # invalid UTF-8 to Rust, it will rightfully complain. Here we do a quick and dirty
@@ -133,7 +120,141 @@ class Encoding:
             # string, but given that this is input we want to support, maybe that's okay.
             # Also we use errors="replace" to handle weird things like lone surrogates.
             text = text.encode("utf-16", "surrogatepass").decode("utf-16", "replace")
-            return self._core_bpe.encode(text, allowed_special)
+            return self._core_bpe.encode_checked(text, allowed_special, disallowed_special)
+
+    def encode_with_offsets(
+        self,
+        text: str,
+        *,
+        allowed_special: Literal["all"] | AbstractSet[str] = set(),  # noqa: B006
+        disallowed_special: Literal["all"] | Collection[str] = "all",
+        unit: Literal["bytes", "chars", "utf16"] = "bytes",
+    ) -> list[tuple[int, int, int]]:
+        """Encodes a string into tokens, along with the span of text each token came from.
+
+        Returns a list of `(token, start, end)`. By default, `start` and `end` are byte offsets
+        into the UTF-8 encoding of `text`. With `unit="chars"` they are indices into `text`, and
+        with `unit="utf16"` they are UTF-16 code unit indices, as used by JavaScript.
+
+        If a token boundary falls inside a character, the token's span is widened to include the
+        whole character, so tokens that split a character have overlapping spans. Special token
+        handling is the same as in `encode`.
+
+        ```
+        >>> enc.encode_with_offsets("hello world")
+        [(31373, 0, 5), (995, 5, 11)]
+        ```
+        """
+        try:
+            return self._core_bpe.encode_with_offsets(
+                text, allowed_special, disallowed_special, unit
+            )
+        except UnicodeEncodeError:
+            # See comment in encode. Offsets are then into the fixed up text.
+            text = text.encode("utf-16", "surrogatepass").decode("utf-16", "replace")
+            return self._core_bpe.encode_with_offsets(
+                text, allowed_special, disallowed_special, unit
+            )
+
+    def encode_truncated(
+        self,
+        text: str,
+        max_tokens: int,
+        *,
+        keep: Literal["head", "tail", "middle"] = "head",
+        ellipsis: str = "…",
+        allowed_special: Literal["all"] | AbstractSet[str] = set(),  # noqa: B006
+        disallowed_special: Literal["all"] | Collection[str] = "all",
+    ) -> tuple[list[int], int, int]:
+        """Encodes a string into at most `max_tokens` tokens, cutting the text if it does not fit.
+
+        `keep` says which part of the text is kept: the start, the end, or both ends joined by
+        `ellipsis`. Returns `(tokens, head_end, tail_start)`, where the kept text is
+        `text[:head_end]`, then the ellipsis if the text was cut in the middle, then
+        `text[tail_start:]`. If nothing was cut, `head_end == tail_start == len(text)`.
+
+        Cuts are made on token boundaries, and the tokens are the encoding of the kept text, so
+        unlike slicing `enc.encode(text)`, they decode back to exactly the kept text.
+
+        ```
+        >>> enc.encode_truncated("hello world", 1)
+        ([31373], 5, 11)
+        >>> enc.encode_truncated("hello world", 1, keep="tail")
+        ([995], 0, 5)
+        ```
+        """
+        try:
+            return self._core_bpe.encode_truncated(
+                text, allowed_special, disallowed_special, max_tokens, keep, ellipsis
+            )
+        except UnicodeEncodeError:
+            # See comment in encode. Offsets are then into the fixed up text.
+            text = text.encode("utf-16", "surrogatepass").decode("utf-16", "replace")
+            ellipsis = ellipsis.encode("utf-16", "surrogatepass").decode("utf-16", "replace")
+            return self._core_bpe.encode_truncated(
+                text, allowed_special, disallowed_special, max_tokens, keep, ellipsis
+            )
+
+    def chunk(
+        self, text: str, max_tokens: int, *, overlap: int = 0
+    ) -> list[tuple[list[int], int, int]]:
+        """Splits a string into chunks of at most `max_tokens` tokens.
+
+        Returns `(tokens, start, end)` for each chunk, where `tokens` is the encoding of
+        `text[start:end]`. Consecutive chunks share up to `overlap` tokens. Chunks end at
+        paragraph breaks, line breaks, whitespace or regex piece boundaries where possible, and
+        never split a character. Special tokens are encoded as ordinary text.
+
+        ```
+        >>> enc.chunk("hello world", 1)
+        [([31373], 0, 5), ([995], 5, 11)]
+        ```
+        """
+        try:
+            return self._core_bpe.chunk(text, max_tokens, overlap)
+        except UnicodeEncodeError:
+            # See comment in encode. Offsets are then into the fixed up text.
+            text = text.encode("utf-16", "surrogatepass").decode("utf-16", "replace")
+            return self._core_bpe.chunk(text, max_tokens, overlap)
+
+    def count_tokens(
+        self,
+        text: str,
+        *,
+        allowed_special: Literal["all"] | AbstractSet[str] = set(),  # noqa: B006
+        disallowed_special: Literal["all"] | Collection[str] = "all",
+    ) -> int:
+        """Returns `len(enc.encode(text, ...))`, without building the list of tokens.
+
+        ```
+        >>> enc.count_tokens("hello world")
+        2
+        ```
+        """
+        try:
+            return self._core_bpe.count_tokens(text, allowed_special, disallowed_special)
+        except UnicodeEncodeError:
+            # See comment in encode
+            text = text.encode("utf-16", "surrogatepass").decode("utf-16", "replace")
+            return self._core_bpe.count_tokens(text, allowed_special, disallowed_special)
+
+    def count_tokens_up_to(self, text: str, limit: int) -> int | None:
+        """Returns `len(enc.encode_ordinary(text))` if it is at most `limit`, otherwise None.
+
+        This stops as soon as the limit is passed, so it is cheap to check whether a long text
+        fits in a context window.
+
+        ```
+        >>> enc.count_tokens_up_to("hello world", 1)
+        None
+        ```
+        """
+        try:
+            return self._core_bpe.count_tokens_up_to(text, limit)
+        except UnicodeEncodeError:
+            # See comment in encode
+            text = text.encode("utf-16", "surrogatepass").decode("utf-16", "replace")
+            return self._core_bpe.count_tokens_up_to(text, limit)
 
     def encode_to_numpy(
         self,
@@ -146,19 +267,9 @@ class Encoding:
 
         Avoids the overhead of copying the token buffer into a Python list.
         """
-        if allowed_special == "all":
-            allowed_special = self.special_tokens_set
-        if disallowed_special == "all":
-            disallowed_special = self.special_tokens_set - allowed_special
-        if disallowed_special:
-            if not isinstance(disallowed_special, frozenset):
-                disallowed_special = frozenset(disallowed_special)
-            if match := _special_token_regex(disallowed_special).search(text):
-                raise_disallowed_special_token(match.group())
-
         import numpy as np
 
-        buffer = self._core_bpe.encode_to_tiktoken_buffer(text, allowed_special)
+        buffer = self._core_bpe.encode_to_tiktoken_buffer(text, allowed_special, disallowed_special)
         return np.frombuffer(buffer, dtype=np.uint32)
 
     def encode_ordinary_batch(self, text: list[str], *, num_threads: int = 8) -> list[list[int]]:
@@ -171,9 +282,12 @@ class Encoding:
         [[31373, 995], [11274, 16390, 995]]
         ```
         """
-        encoder = functools.partial(self.encode_ordinary)
-        with ThreadPoolExecutor(num_threads) as e:
-            return list(e.map(encoder, text))
+        try:
+            return self._core_bpe.encode_ordinary_batch(text, num_threads)
+        except UnicodeEncodeError:
+            # See comment in encode
+            text = [t.encode("utf-16", "surrogatepass").decode("utf-16", "replace") for t in text]
+            return self._core_bpe.encode_ordinary_batch(text, num_threads)
 
     def encode_batch(
         self,
@@ -192,18 +306,43 @@ class Encoding:
         [[31373, 995], [11274, 16390, 995]]
         ```
         """
-        if allowed_special == "all":
-            allowed_special = self.special_tokens_set
-        if disallowed_special == "all":
-            disallowed_special = self.special_tokens_set - allowed_special
-        if not isinstance(disallowed_special, frozenset):
-            disallowed_special = frozenset(disallowed_special)
-
-        encoder = functools.partial(
-            self.encode, allowed_special=allowed_special, disallowed_special=disallowed_special
-        )
-        with ThreadPoolExecutor(num_threads) as e:
-            return list(e.map(encoder, text))
+        try:
+            return self._core_bpe.encode_batch(
+                text, allowed_special, disallowed_special, num_threads
+            )
+        except UnicodeEncodeError:
+            # See comment in encode
+            text = [t.encode("utf-16", "surrogatepass").decode("utf-16", "replace") for t in text]
+            return self._core_bpe.encode_batch(
+                text, allowed_special, disallowed_special, num_threads
+            )
+
+    def encode_parallel(
+        self,
+        text: str,
+        *,
+        num_threads: int = 8,
+        allowed_special: Literal["all"] | AbstractSet[str] = set(),  # noqa: B006
+        disallowed_special: Literal["all"] | Collection[str] = "all",
+    ) -> list[int]:
+        """Encodes a single large string into tokens, in parallel.
+
+        The string is split where that cannot change the result, so this returns the same tokens
+        as `encode`. Only the encodings in `tiktoken_ext.openai_public` are split, and strings
+        shorter than about a hundred kilobytes are encoded on a single thread.
+
+        See `encode` for more details on `allowed_special` and `disallowed_special`.
+        """
+        try:
+            return self._core_bpe.encode_parallel(
+                text, allowed_special, disallowed_special, num_threads
+            )
+        except UnicodeEncodeError:
+            # See comment in encode
+            text = text.encode("utf-16", "surrogatepass").decode("utf-16", "replace")
+            return self._core_bpe.encode_parallel(
+                text, allowed_special, disallowed_special, num_threads
+            )
 
     def encode_with_unstable(
         self,
@@ -230,17 +369,7 @@ class Encoding:
         >>> assert all(enc.decode_bytes(stable_tokens + seq).startswith(text.encode()) for seq in completions)
         ```
         """
-        if allowed_special == "all":
-            allowed_special = self.special_tokens_set
-        if disallowed_special == "all":
-            disallowed_special = self.special_tokens_set - allowed_special
-        if disallowed_special:
-            if not isinstance(disallowed_special, frozenset):
-                disallowed_special = frozenset(disallowed_special)
-            if match := _special_token_regex(disallowed_special).search(text):
-                raise_disallowed_special_token(match.group())
-
-        return self._core_bpe.encode_with_unstable(text, allowed_special)
+        return self._core_bpe.encode_with_unstable(text, allowed_special, disallowed_special)
 
     def encode_single_token(self, text_or_bytes: str | bytes) -> int:
         """Encodes text corresponding to a single token to its token value.
@@ -309,6 +438,22 @@ class Encoding:
         """
         return [self.decode_single_token_bytes(token) for token in tokens]
 
+    def streaming_decoder(self) -> _tiktoken.StreamingDecoder:
+        """Returns a decoder that takes tokens one at a time and returns complete text.
+
+        Bytes of a character that is split across tokens are held back until the character is
+        complete. `finish` returns what is left, with an incomplete character replaced by U+FFFD.
+
+        ```
+        >>> decoder = enc.streaming_decoder()
+        >>> decoder.push(31373)
+        'hello'
+        >>> decoder.finish()
+        ''
+        ```
+        """
+        return _tiktoken.StreamingDecoder(self._core_bpe)
+
     def decode_with_offsets(self, tokens: Sequence[int]) -> tuple[str, list[int]]:
         """Decodes a list of tokens into a string and a list of offsets.
 
@@ -338,16 +483,16 @@ class Encoding:
         self, batch: Sequence[Sequence[int]], *, errors: str = "replace", num_threads: int = 8
     ) -> list[str]:
         """Decodes a batch (list of lists of tokens) into a list of strings."""
-        decoder = functools.partial(self.decode, errors=errors)
-        with ThreadPoolExecutor(num_threads) as e:
-            return list(e.map(decoder, batch))
+        return [
+            b.decode("utf-8", errors=errors)
+            for b in self._core_bpe.decode_bytes_batch(batch, num_threads)
+        ]
 
     def decode_bytes_batch(
         self, batch: Sequence[Sequence[int]], *, num_threads: int = 8
     ) -> list[bytes]:
         """Decodes a batch (list of lists of tokens) into a list of bytes."""
-        with ThreadPoolExecutor(num_threads) as e:
-            return list(e.map(self.decode_bytes, batch))
+        return self._core_bpe.decode_bytes_batch(batch, num_threads)
 
     # ====================
     # Miscellaneous
@@ -426,24 +571,3 @@ class Encoding:
             self.__dict__ = tiktoken.registry.get_encoding(value).__dict__
             return
         self.__init__(**value)
-
-
-@functools.lru_cache(maxsize=128)
-def _special_token_regex(tokens: frozenset[str]) -> re.Pattern[str]:
-    try:
-        import regex as re
-    except ImportError:
-        import re
-    inner = "|".join(re.escape(token) for token in tokens)
-    return re.compile(f"({inner})")
-
-
-def raise_disallowed_special_token(token: str) -> NoReturn:
-    raise ValueError(
-        f"Encountered text corresponding to disallowed special token {token!r}.\n"
-        "If you want this text to be encoded as a special token, "
-        f"pass it to `allowed_special`, e.g. `allowed_special={{{token!r}, ...}}`.\n"
-        f"If you want this text to be encoded as normal text, disable the check for this token "
-        f"by passing `disallowed_special=(enc.special_tokens_set - {{{token!r}}})`.\n"
-        "To disable this check for all special tokens, pass `disallowed_special=()`.\n"
-    )